@@ -0,0 +1,145 @@
+//! Automatic trading halt. A market whose outcome prices move too far, in a
+//! single trade or within a time window, is moved to `Stage::Paused` until
+//! its operator reopens it.
+//!
+//! The trade that crosses a limit is not reverted. Rejecting it would roll
+//! back the pause with it, so that trade executes and every trade after it
+//! is refused until the market is reopened.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log};
+
+use crate::market::*;
+
+/// Price move limits, scaled by 10^18 like the prices themselves
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CircuitBreaker {
+    /// A trade moving any outcome price further than this pauses the market
+    pub max_trade_move: U128,
+    /// A trade taking any outcome price further than this from any price it
+    /// had within the window pauses the market
+    pub max_window_move: U128,
+    /// Length of the window in nanoseconds. The window rolls in steps of
+    /// `window / WINDOW_SLOTS`: it covers the slot of the trade being checked
+    /// and the slots right before it.
+    pub window: U64,
+}
+
+/// Number of slots the window is split into. The window keeps the lowest and
+/// highest price of each slot, so its size doesn't depend on the number of
+/// trades.
+pub const WINDOW_SLOTS: u64 = 8;
+
+/// Lowest and highest outcome prices before the trades of one time slot
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceSlot {
+    /// Index of the slot since the epoch, `u64::MAX` while unused
+    pub slot: U64,
+    pub low: Vec<U128>,
+    pub high: Vec<U128>,
+}
+
+/// Price range of the last `WINDOW_SLOTS` slots, reused in turn. Prices only
+/// change with trades, so these ranges cover every price the window has seen.
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceWindow {
+    pub slots: Vec<PriceSlot>,
+}
+
+impl PriceWindow {
+    /// Empty window with all its slots allocated, so trades don't grow it
+    pub fn new(num_outcomes: u64) -> Self {
+        let unused = PriceSlot {
+            slot: U64(u64::MAX),
+            low: vec![U128(0); num_outcomes as usize],
+            high: vec![U128(0); num_outcomes as usize],
+        };
+        Self {
+            slots: vec![unused; WINDOW_SLOTS as usize],
+        }
+    }
+
+    /// Adds `prices` to the range of slot `slot`, first clearing the entry
+    /// if it last held an older slot
+    fn record(&mut self, slot: u64, prices: &[u128]) {
+        let entry = &mut self.slots[(slot % WINDOW_SLOTS) as usize];
+        if entry.slot.0 != slot {
+            entry.slot = U64(slot);
+            entry.low = prices.iter().copied().map(U128).collect();
+            entry.high = entry.low.clone();
+            return;
+        }
+        for ((low, high), price) in entry.low.iter_mut().zip(&mut entry.high).zip(prices) {
+            low.0 = low.0.min(*price);
+            high.0 = high.0.max(*price);
+        }
+    }
+
+    /// Largest distance of `prices` from any price seen in the slots within
+    /// the window ending with slot `slot`
+    fn max_move(&self, slot: u64, prices: &[u128]) -> u128 {
+        self.slots
+            .iter()
+            .filter(|entry| entry.slot.0 <= slot && slot - entry.slot.0 < WINDOW_SLOTS)
+            .map(|entry| {
+                let low: Vec<u128> = entry.low.iter().map(|p| p.0).collect();
+                let high: Vec<u128> = entry.high.iter().map(|p| p.0).collect();
+                max_move(&low, prices).max(max_move(&high, prices))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn max_move(before: &[u128], after: &[u128]) -> u128 {
+    before
+        .iter()
+        .zip(after)
+        .map(|(b, a)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0)
+}
+
+impl Market {
+    /// Prices a trade is checked against, if the market has a circuit breaker
+    pub(crate) fn breaker_reference(&self) -> Option<Vec<u128>> {
+        self.circuit_breaker
+            .as_ref()
+            .map(|_| self.calculate_prices())
+    }
+
+    /// Pauses trading if the trade that moved prices away from
+    /// `prices_before` exceeded a limit. The trade itself stands.
+    pub(crate) fn check_circuit_breaker(&mut self, prices_before: Option<Vec<u128>>) {
+        let (breaker, prices_before) = match (&self.circuit_breaker, prices_before) {
+            (Some(breaker), Some(prices)) => (breaker.clone(), prices),
+            _ => return,
+        };
+        let slot_length = (breaker.window.0 / WINDOW_SLOTS).max(1);
+        let slot = env::block_timestamp() / slot_length;
+        let prices = self.calculate_prices();
+        let num_outcomes = self.num_outcomes();
+        let window = self
+            .price_window
+            .get_or_insert_with(|| PriceWindow::new(num_outcomes));
+        window.record(slot, &prices_before);
+
+        let trade_move = max_move(&prices_before, &prices);
+        let window_move = window.max_move(slot, &prices);
+        if trade_move > breaker.max_trade_move.0 || window_move > breaker.max_window_move.0 {
+            log!(
+                "Circuit breaker paused market {}: prices moved {} in the trade and {} in the window",
+                self.id,
+                trade_move,
+                window_move
+            );
+            self.stage = Stage::Paused;
+            self.pause_reason = Some("Circuit breaker".into());
+        }
+    }
+}
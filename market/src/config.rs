@@ -0,0 +1,207 @@
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::*;
+
+/// Global settings markets are created and opened with. Updated by the owner.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    /// Liquidity parameter of markets created without one, in whole
+    /// collateral tokens
+    pub default_liquidity: U128,
+    /// Subsidy a market must hold to open, in whole collateral tokens
+    pub minimum_deposit: U128,
+    /// Decimals of collateral precision the prices of new markets are
+    /// rounded to. Collateral tokens need at least this many.
+    pub rounding_decimals: u32,
+    /// Highest trade fee a market can be created with
+    pub max_trade_fee_bps: u16,
+    /// Part of every trade fee that goes to the protocol
+    pub protocol_fee_share_bps: u16,
+    /// Highest part of the trade fee a market can pay to its fee owner
+    pub max_creator_fee_share_bps: u16,
+    /// NEAR attached to `create_market`, returned to the creator once the
+    /// market is finalized
+    pub creation_bond: U128,
+    /// Time after a market's resolution time from which a resolver of last
+    /// resort can settle it, in nanoseconds
+    pub last_resort_delay: U64,
+    /// Copied to new markets. Defaults to none.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Account protocol fees are paid to. Defaults to the owner.
+    pub protocol_fee_recipient: Option<AccountId>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_liquidity: U128(50),
+            minimum_deposit: U128(100),
+            rounding_decimals: 4,
+            max_trade_fee_bps: 1_000,
+            protocol_fee_share_bps: 1_000,
+            max_creator_fee_share_bps: 9_000,
+            creation_bond: U128(10u128.pow(24)),
+            last_resort_delay: U64(7 * 24 * 60 * 60 * 1_000_000_000),
+            circuit_breaker: None,
+            protocol_fee_recipient: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn assert_valid(&self) {
+        assert!(
+            self.max_trade_fee_bps as u128 <= BPS_DENOMINATOR,
+            "Trade fee cap exceeds 100%"
+        );
+        assert!(
+            self.protocol_fee_share_bps as u128 + self.max_creator_fee_share_bps as u128
+                <= BPS_DENOMINATOR,
+            "Fee shares exceed the whole fee"
+        );
+    }
+
+    /// `amount` whole tokens in base units of a token with `decimals`
+    pub fn base_units(amount: U128, decimals: u32) -> Balance {
+        amount.0.checked_mul(10u128.pow(decimals)).unwrap()
+    }
+
+    pub fn last_resort_time(&self, resolution_time: Timestamp) -> Timestamp {
+        resolution_time.saturating_add(self.last_resort_delay.0)
+    }
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// Manages collateral tokens and pays out protocol fees
+    Admin,
+    /// Creates markets
+    MarketCreator,
+    /// Settles markets the regular resolution failed to settle in time
+    Resolver,
+}
+
+// internal methods
+impl Contract {
+    /// The owner holds every role
+    pub(crate) fn has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        *account_id == self.owner_id
+            || self
+                .roles
+                .get(account_id)
+                .is_some_and(|roles| roles.contains(&role))
+    }
+
+    pub(crate) fn assert_role(&self, role: Role) {
+        assert!(
+            self.has_role(&env::predecessor_account_id(), role),
+            "Missing role {:?}",
+            role
+        );
+    }
+
+    /// Trading, deposits and resolution are blocked while the contract is
+    /// paused
+    pub(crate) fn assert_not_paused(&self) {
+        assert!(!self.paused, "Contract is paused");
+    }
+
+    pub(crate) fn assert_markets_migrated(&self) {
+        assert!(
+            self.unmigrated_markets.is_none(),
+            "Markets are being migrated"
+        );
+    }
+
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can do this"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new(owner_id: ValidAccountId, config: Option<Config>) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        let config = config.unwrap_or_default();
+        config.assert_valid();
+        Self {
+            state_version: STATE_VERSION,
+            owner_id: owner_id.into(),
+            roles: UnorderedMap::new(b"roles".to_vec()),
+            config,
+            paused: false,
+            markets: LookupMap::new(b"markets".to_vec()),
+            market_metadata: LookupMap::new(b"metadata".to_vec()),
+            num_markets: 0,
+            unmigrated_markets: None,
+            collateral_balances: LookupMap::new(b"collateral".to_vec()),
+            collateral_tokens: UnorderedMap::new(b"tokens".to_vec()),
+            storage_accounts: LookupMap::new(b"storage".to_vec()),
+            market_storage_growth: 0,
+        }
+    }
+
+    /// Replaces the global config. Existing markets keep the settings they
+    /// were created with. Only the owner can do this.
+    pub fn set_config(&mut self, config: Config) {
+        self.assert_owner();
+        config.assert_valid();
+        self.config = config;
+    }
+
+    /// Pauses or resumes the whole contract. While paused only views and
+    /// withdrawals of settled funds work. Only the owner can do this.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        log!("Contract paused: {}", paused);
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner();
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(&account_id, &roles);
+        }
+    }
+
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner();
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        roles.retain(|r| *r != role);
+        if roles.is_empty() {
+            self.roles.remove(&account_id);
+        } else {
+            self.roles.insert(&account_id, &roles);
+        }
+    }
+
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Roles granted to `account_id`. The owner holds all roles implicitly.
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+}
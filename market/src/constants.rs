@@ -2,7 +2,22 @@ use near_sdk::Gas;
 
 pub const ONE_YOCTO: u128 = 1;
 pub const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+pub const GAS_FOR_RESOLVE_TRANSFER: Gas = 10_000_000_000_000;
+pub const GAS_FOR_ORACLE_REQUEST: Gas = 10_000_000_000_000;
+pub const GAS_FOR_ORACLE_CALLBACK: Gas = 10_000_000_000_000;
+pub const GAS_FOR_FT_METADATA: Gas = 10_000_000_000_000;
+pub const GAS_FOR_FT_METADATA_CALLBACK: Gas = 10_000_000_000_000;
 
-pub const MINIMUM_DEPOSIT: u128 = 100;
-pub const DEFAULT_LIQUIDITY: f64 = 50.0;
-pub const ROUNDING_DECIMALS: u32 = 4;
+/// Gas burnt to schedule one transfer together with its callback
+pub const GAS_FOR_SCHEDULING_TRANSFER: Gas = 20_000_000_000_000;
+/// Gas `redeem_all` keeps for its own work after picking the markets
+pub const GAS_FOR_REDEEM_ALL: Gas = 10_000_000_000_000;
+
+/// Fee rates and shares are expressed in basis points of this
+pub const BPS_DENOMINATOR: u128 = 10_000;
+
+/// Bytes reserved for an account's storage registration itself
+pub const ACCOUNT_STORAGE_BYTES: u64 = 200;
+
+/// Layout version of the contract state written by this code
+pub const STATE_VERSION: u32 = 2;
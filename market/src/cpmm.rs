@@ -0,0 +1,236 @@
+//! Fixed-product market maker (the Gnosis CPMM) expressed as a cost function.
+//!
+//! The pool starts with `funding` tokens of every outcome. A purchase with
+//! `a` collateral mints `a` complete sets into the pool and takes out as many
+//! tokens of the bought outcome as keeps the product of the pool balances at
+//! `funding^n`; a sale does the reverse. Once traders hold `q` and `N` net
+//! collateral has come in, the pool holds `funding + N - q_j` of outcome `j`,
+//! so the invariant prod(funding + N - q_j) = funding^n fixes N = C(q). The
+//! pool can never pay out more than its tokens, so the loss is bounded by
+//! `funding`.
+//!
+//! Units and rounding follow the `lmsr` module.
+
+use std::convert::TryFrom;
+
+use crate::math::{self, ONE};
+
+/// sum(ln(funding + n_collateral - q_j)) - n * ln(funding), in fixed point.
+/// Increasing in `n_collateral` and zero at the cost.
+fn invariant_gap(funding: u128, volumes: &[u128], n_collateral: u128) -> i128 {
+    let pool: i128 = volumes
+        .iter()
+        .map(|&q| i128::try_from(math::ln_int(funding + n_collateral - q)).unwrap())
+        .sum();
+    let target = i128::try_from(math::ln_int(funding)).unwrap() * volumes.len() as i128;
+    pool - target
+}
+
+/// Required subsidy: the pool's initial complete sets
+pub fn fund(funding: u128) -> u128 {
+    funding
+}
+
+/// Upper bound on the absolute error of [`cost`] for `n` outcomes
+pub fn cost_error(funding: u128, n: usize) -> u128 {
+    // the gap is accurate to (n + 1) * (LN_ERROR + 1) and its slope in the
+    // collateral is sum(1 / pool_j) >= 1 / funding, since some pool balance
+    // is always at most `funding`
+    let gap_error = (n as u128 + 1) * (math::LN_ERROR + 1);
+    math::mul_div_ceil(funding, gap_error, ONE) + 1
+}
+
+/// Net collateral N that has to come in for traders to hold `volumes`
+pub fn cost(funding: u128, volumes: &[u128]) -> u128 {
+    solve_cost(funding, volumes).0
+}
+
+/// [`cost`] together with the number of Newton steps it took.
+///
+/// The invariant is a polynomial of degree n in N, so there is no closed
+/// form for every market. The gap is increasing and concave in N, so Newton
+/// steps from below never pass the root and close in on it quadratically.
+/// The first guess already holds every other pool at its largest, which
+/// leaves few steps even when a pool starts out nearly empty.
+fn solve_cost(funding: u128, volumes: &[u128]) -> (u128, u32) {
+    // the outcome traders hold most of has the smallest pool
+    let min_index = match volumes.iter().enumerate().max_by_key(|(_, &q)| q) {
+        Some((j, _)) => j,
+        None => return (0, 0),
+    };
+    let max = volumes[min_index];
+
+    // lower bounds: the pools average at least `funding`, since their
+    // geometric mean is `funding`, and the smallest pool holds at least what
+    // keeps the product with every other pool as full as it gets at N = max
+    let mean = volumes.iter().sum::<u128>() / volumes.len() as u128;
+    let mut min_pool = funding;
+    for (j, &q) in volumes.iter().enumerate() {
+        if j != min_index {
+            min_pool = math::mul_div(min_pool, funding, funding + max - q);
+        }
+    }
+    let mut lo = mean.max((max + min_pool.max(1)).saturating_sub(funding));
+    // every pool holds at least `funding` at N = max
+    let hi = max;
+
+    let mut steps = 0;
+    loop {
+        let gap = invariant_gap(funding, volumes, lo);
+        if gap >= 0 || lo >= hi {
+            return (lo.min(hi), steps);
+        }
+        // the gap's slope in N is sum(1 / pool_j) = sum(w_j) / (ONE * min),
+        // with w_j = ONE * min / pool_j. Rounding the slope up keeps the step
+        // short of the root.
+        let pools = volumes
+            .iter()
+            .map(|&q| funding + lo - q)
+            .collect::<Vec<_>>();
+        let min = pools.iter().copied().min().unwrap();
+        let weights: u128 = pools
+            .iter()
+            .map(|&pool| math::mul_div_ceil(min, ONE, pool))
+            .sum();
+        let step = math::mul_div(gap.unsigned_abs(), min, weights);
+        lo = lo.checked_add(step.max(1)).unwrap();
+        steps += 1;
+    }
+}
+
+/// Volume at `index` that `amount` of collateral buys (Gnosis
+/// `calcBuyAmount`): the pool takes in `amount` complete sets and pays out
+/// as many tokens of the outcome as keeps the product of its balances. The
+/// pool balance is rounded up, so the buyer never gets more than that.
+pub fn volume(funding: u128, origin: &[u128], index: usize, amount: u128) -> u128 {
+    let n_collateral = cost(funding, origin);
+    let pool = funding + n_collateral - origin[index];
+    let mut pool_after = pool;
+    for (j, &q) in origin.iter().enumerate() {
+        if j != index {
+            let other = funding + n_collateral - q;
+            pool_after = math::mul_div_ceil(pool_after, other, other + amount);
+        }
+    }
+    (pool + amount).saturating_sub(pool_after)
+}
+
+/// Marginal prices, in fixed point: p_i = (1 / pool_i) / sum(1 / pool_j)
+pub fn compute_price(funding: u128, volumes: &[u128]) -> Vec<u128> {
+    let n_collateral = cost(funding, volumes);
+    let pools = volumes
+        .iter()
+        .map(|&q| funding + n_collateral - q)
+        .collect::<Vec<_>>();
+    let min = pools.iter().copied().min().unwrap_or(0);
+
+    let weights = pools
+        .iter()
+        .map(|&pool| math::mul_div(min, ONE, pool))
+        .collect::<Vec<_>>();
+    let sum: u128 = weights.iter().sum();
+
+    weights
+        .iter()
+        .map(|&w| math::mul_div(w, ONE, sum))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::U256;
+    use crate::mechanism::{Cpmm, MarketMaker};
+
+    const FUNDING: u128 = 100 * ONE;
+
+    #[test]
+    fn empty_market() {
+        let volumes = [0, 0, 0];
+        assert_eq!(cost(FUNDING, &volumes), 0);
+        let prices = compute_price(FUNDING, &volumes);
+        assert!(prices.iter().all(|&p| p == ONE / 3));
+    }
+
+    #[test]
+    fn matches_fixed_product_formula() {
+        // Gnosis calcBuyAmount for two outcomes: the pool [F, F] takes `a`
+        // complete sets and gives out enough of outcome 0 to keep F^2
+        let amount = 30 * ONE;
+        let pool_0 = math::mul_div(FUNDING, FUNDING, FUNDING + amount);
+        let expected = FUNDING + amount - pool_0;
+
+        let mm = Cpmm { funding: FUNDING };
+        let bought = mm.volume(&[0, 0], 0, amount);
+        let diff = bought.abs_diff(expected);
+        assert!(diff <= cost_error(FUNDING, 2) * 4);
+
+        let mut after = [0, 0];
+        after[0] = expected;
+        let prices = compute_price(FUNDING, &after);
+        // p_0 = pool_1 / (pool_0 + pool_1)
+        let p_0 = math::mul_div(FUNDING + amount, ONE, FUNDING + amount + pool_0);
+        assert!(prices[0] + 1_000 > p_0 && prices[0] < p_0 + 1_000);
+    }
+
+    #[test]
+    fn volume_equal_to_funding() {
+        // the pool of outcome 0 is empty before any collateral comes in, and
+        // N * (FUNDING + N) = FUNDING^2 at the cost
+        let volumes = [FUNDING, 0];
+        let n_collateral = cost(FUNDING, &volumes);
+        let error = cost_error(FUNDING, 2);
+        let product = |n: u128| U256::from(n) * U256::from(FUNDING + n);
+        let target = U256::from(FUNDING) * U256::from(FUNDING);
+        assert!(product(n_collateral - error) < target);
+        assert!(product(n_collateral + error) >= target);
+        let prices = compute_price(FUNDING, &volumes);
+        assert!(prices[0] > prices[1]);
+    }
+
+    #[test]
+    fn cost_takes_few_steps() {
+        for &funding in &[FUNDING, 10u128.pow(30)] {
+            let cases: [&[u128]; 5] = [
+                &[funding, 0],
+                &[funding - 1, 0, 0],
+                &[10 * funding, 0],
+                &[1_000 * funding, 3 * funding, 0, 7],
+                &[funding / 3, funding / 2, funding, 2 * funding],
+            ];
+            for volumes in cases.iter() {
+                let (n_collateral, steps) = solve_cost(funding, volumes);
+                assert!(steps <= 12, "{} steps for {:?}", steps, volumes);
+                let gap = invariant_gap(funding, volumes, n_collateral);
+                assert!(gap >= 0);
+                let short = n_collateral - cost_error(funding, volumes.len());
+                assert!(invariant_gap(funding, volumes, short) < 0);
+            }
+        }
+    }
+
+    #[test]
+    fn volume_buys_the_amount() {
+        let mm = Cpmm { funding: FUNDING };
+        let volumes = [40 * ONE, 0, 10 * ONE];
+        for &amount in &[ONE / 3, 25 * ONE, 10_000 * FUNDING] {
+            let bought = mm.volume(&volumes, 1, amount);
+            let paid = mm.estimate(&volumes, 1, bought as i128);
+            let error = 4 * cost_error(FUNDING, 3) as i128;
+            assert!((paid - amount as i128).abs() <= error);
+        }
+    }
+
+    #[test]
+    fn round_trip_never_profits() {
+        let mm = Cpmm { funding: FUNDING };
+        let volumes = [40 * ONE, 0, 10 * ONE];
+        let amount = 25 * ONE;
+        let paid = mm.estimate(&volumes, 1, amount as i128);
+        let mut after = volumes.to_vec();
+        after[1] += amount;
+        let received = -mm.estimate(&after, 1, -(amount as i128));
+        assert!(paid > 0);
+        assert!(received <= paid);
+    }
+}
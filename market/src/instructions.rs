@@ -1,5 +1,8 @@
+use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::Balance;
+use near_sdk::AccountId;
+
+use crate::market::Timestamp;
 
 /// Message parameters to receive via token function call.
 #[derive(Serialize, Deserialize)]
@@ -7,7 +10,12 @@ use near_sdk::Balance;
 #[serde(tag = "type")]
 pub enum Instruction {
     Buy(Buy),
+    BuyWithCollateral(BuyWithCollateral),
     InitialDeposit(InitialDeposit),
+    MintCompleteSets(MintCompleteSets),
+    ProposeResolution(ProposeResolution),
+    DisputeResolution(DisputeResolution),
+    Deposit(Deposit),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -16,18 +24,64 @@ pub struct Buy {
     pub market_id: u64,
     pub outcome_id: u32,
     pub num_shares: u64,
+    /// The whole amount is refunded if the shares cost more than this,
+    /// including fees
+    pub max_cost: Option<U128>,
+    /// unix ts in nanoseconds after which the whole amount is refunded
+    pub valid_until: Option<Timestamp>,
 }
 
+/// Spend the transferred amount on as many shares as it affords. Whatever is
+/// left after the purchase is refunded.
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
-pub struct Sell {
+pub struct BuyWithCollateral {
     pub market_id: u64,
     pub outcome_id: u32,
-    pub num_shares: u64,
+    /// The whole amount is refunded if it affords fewer shares than this
+    pub min_shares: u64,
+    /// unix ts in nanoseconds after which the whole amount is refunded
+    pub valid_until: Option<Timestamp>,
 }
 
+/// Add liquidity to a pending market. The sender receives one LP share per
+/// unit of collateral.
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct InitialDeposit {
     pub market_id: u64,
 }
+
+/// Exchange the transferred amount for complete sets, one share of every
+/// outcome per share unit of collateral. Any remainder is refunded.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MintCompleteSets {
+    pub market_id: u64,
+}
+
+/// Propose the payouts of an optimistic market as its oracle. The transferred
+/// amount is the bond.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ProposeResolution {
+    pub market_id: u64,
+    pub payouts: Vec<U128>,
+}
+
+/// Dispute the proposed payouts of an optimistic market. The transferred
+/// amount is the bond and must exceed the proposal bond.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DisputeResolution {
+    pub market_id: u64,
+}
+
+/// Credit the transferred amount to an internal collateral balance, from
+/// which trades settle without a token transfer each
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Deposit {
+    /// Account to credit. Defaults to the sender.
+    pub account_id: Option<AccountId>,
+}
@@ -0,0 +1,171 @@
+use crate::*;
+
+// internal methods
+impl Contract {
+    pub(crate) fn collateral_balance(
+        &self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+    ) -> Balance {
+        self.collateral_balances
+            .get(&(account_id.clone(), token_id.clone()))
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn internal_deposit_collateral(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let balance = self.collateral_balance(account_id, token_id);
+        self.collateral_balances.insert(
+            &(account_id.clone(), token_id.clone()),
+            &balance.checked_add(amount).unwrap(),
+        );
+    }
+
+    pub(crate) fn internal_withdraw_collateral(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let balance = self.collateral_balance(account_id, token_id);
+        let balance = balance.checked_sub(amount).expect("Not enough collateral");
+        self.collateral_balances
+            .insert(&(account_id.clone(), token_id.clone()), &balance);
+    }
+
+    pub(crate) fn credit_balance(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        ix: instructions::Deposit,
+    ) -> PromiseOrValue<U128> {
+        assert!(
+            self.collateral_tokens.get(token_id).is_some(),
+            "Collateral token not registered"
+        );
+        let initial_storage = self.storage_snapshot();
+        let account_id = ix.account_id.unwrap_or_else(|| sender_id.clone());
+        self.internal_deposit_collateral(&account_id, token_id, amount);
+        self.internal_charge_storage(&account_id, initial_storage, true);
+
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sends `amount` of the caller's internal collateral balance of
+    /// `token_id` to the caller. The balance is restored if the transfer
+    /// fails.
+    #[payable]
+    pub fn withdraw(&mut self, token_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.internal_withdraw_collateral(&account_id, &token_id, amount.into());
+
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            amount,
+            Some(format!("Withdrawing {} to {}", amount.0, account_id)),
+            &token_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_withdraw_transfer(
+            account_id,
+            token_id,
+            amount,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Restores the withdrawn collateral if the transfer failed
+    #[private]
+    pub fn on_withdraw_transfer(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), 1);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "Transfer of {} {} to {} failed, restoring balance",
+            amount.0,
+            token_id,
+            account_id
+        );
+        self.internal_deposit_collateral(&account_id, &token_id, amount.into());
+    }
+
+    /// Buys shares with the caller's internal collateral balance and returns
+    /// their cost including fees. Panics if the order is not executed.
+    #[payable]
+    pub fn buy_from_balance(&mut self, ix: instructions::Buy) -> U128 {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(ix.market_id);
+        let account_id = env::predecessor_account_id();
+        let token_id = market.collateral_token.clone();
+        let balance = self.collateral_balance(&account_id, &token_id);
+
+        let refund = match market.internal_buy(
+            &account_id,
+            balance,
+            ix.num_shares as u128,
+            ix.outcome_id,
+            ix.max_cost.map(|c| c.into()),
+            ix.valid_until,
+        ) {
+            PromiseOrValue::Value(refund) => refund.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert!(refund < balance, "Order not executed");
+        self.save_market(&market);
+
+        let cost = balance - refund;
+        self.internal_withdraw_collateral(&account_id, &token_id, cost);
+        self.internal_charge_storage(&account_id, initial_storage, true);
+        U128(cost)
+    }
+
+    /// Sells shares into the caller's internal collateral balance and
+    /// returns the proceeds net of fees
+    #[payable]
+    pub fn sell_to_balance(
+        &mut self,
+        market_id: MarketId,
+        outcome_id: OutcomeId,
+        num_shares: u64,
+        min_amount_out: U128,
+    ) -> U128 {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(market_id);
+        let account_id = env::predecessor_account_id();
+
+        let sale = market.internal_sell(
+            &account_id,
+            min_amount_out.into(),
+            num_shares as u128,
+            outcome_id,
+        );
+        market.deposit_fees(sale.fee.0);
+        self.save_market(&market);
+
+        self.internal_deposit_collateral(&account_id, &market.collateral_token, sale.amount.0);
+        self.internal_charge_storage(&account_id, initial_storage, true);
+        sale.amount
+    }
+}
@@ -1,99 +1,798 @@
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::Vector;
-use near_sdk::json_types::U128;
-use near_sdk::{env, log, near_bindgen, serde_json, AccountId, Balance, Promise, PromiseOrValue};
+use near_sdk::collections::{LookupMap, UnorderedMap, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance, Gas,
+    PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
+};
 
+use crate::config::*;
+use crate::constants::*;
 use crate::market::*;
+use crate::resolution::*;
+use crate::storage_impl::StorageAccount;
 
+mod circuit_breaker;
+mod config;
 mod constants;
+mod cpmm;
 mod errors;
 mod instructions;
+mod ledger;
 mod lmsr;
+mod lslmsr;
 mod market;
+mod market_roles;
+mod math;
+mod mechanism;
+mod migration;
+mod registry;
+mod resolution;
 mod storage_impl;
 mod token_receiver;
 mod views;
 
 #[near_bindgen]
-#[derive(BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
-    markets: Vector<Market>,
+    /// Layout version of the contract state, see `migration`
+    state_version: u32,
+    owner_id: AccountId,
+    /// Roles granted by the owner
+    roles: UnorderedMap<AccountId, Vec<Role>>,
+    config: Config,
+    /// Emergency stop set by the owner
+    paused: bool,
+    /// Trading state of every market, loaded by trades
+    markets: LookupMap<MarketId, VersionedMarket>,
+    /// Title, description and outcomes of every market, loaded by views
+    market_metadata: LookupMap<MarketId, MarketMetadata>,
+    num_markets: u64,
+    /// Markets of state version 1 that `migrate_markets` has yet to move.
+    /// No market can be used until all of them are moved.
+    unmigrated_markets: Option<Vector<migration::MarketV1>>,
+    /// Internal collateral balances by account and token
+    collateral_balances: LookupMap<(AccountId, AccountId), Balance>,
+    /// Tokens markets can be created against, with their decimals
+    collateral_tokens: UnorderedMap<AccountId, u32>,
+    /// NEP-145 storage deposits and the storage each account uses
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
+    /// Bytes market records grew by since the last storage snapshot. Market
+    /// records are shared, so this is left out of the storage charged to
+    /// accounts. Not stored.
+    #[borsh_skip]
+    market_storage_growth: i64,
 }
 
-impl Default for Contract {
-    fn default() -> Self {
-        Self {
-            markets: Vector::new(b"near-prediction".to_vec()),
-        }
-    }
+type MarketId = u64;
+
+/// Data request interface of an external oracle contract. The oracle answers
+/// by calling `set_outcome` on the requester.
+#[ext_contract(ext_oracle)]
+pub trait Oracle {
+    fn create_request(&mut self, market_id: U64, num_outcomes: u32) -> U64;
 }
 
-type MarketId = u64;
+#[ext_contract(ext_ft_metadata)]
+pub trait FungibleTokenMetadataProvider {
+    fn ft_metadata(
+        &self,
+    ) -> near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+}
+
+#[ext_contract(ext_self)]
+pub trait SelfCallbacks {
+    fn on_sell_transfer(&mut self, market_id: MarketId, sale: PendingSale) -> U128;
+    fn on_burn_transfer(&mut self, market_id: MarketId, account_id: AccountId, num_sets: U128);
+    fn on_liquidity_transfer(
+        &mut self,
+        market_id: MarketId,
+        account_id: AccountId,
+        lp_shares: U128,
+        amount: U128,
+    );
+    fn on_fee_transfer(&mut self, market_id: MarketId, recipient: FeeRecipient, amount: U128);
+    fn on_bond_transfer(&mut self, market_id: MarketId, account_id: AccountId, amount: U128);
+    fn on_request_created(&mut self, market_id: MarketId);
+    fn on_redeem_transfer(&mut self, redemptions: Vec<PendingRedemption>);
+    fn on_withdraw_transfer(&mut self, account_id: AccountId, token_id: AccountId, amount: U128);
+    fn on_ft_metadata(&mut self, token_id: AccountId) -> u8;
+}
 
 #[near_bindgen]
 impl Contract {
+    /// Creates a market with the current config. The attached deposit must
+    /// cover the config's creation bond. Only market creators can do this.
     #[payable]
     pub fn create_market(&mut self, args: CreateMarketArgs) -> MarketId {
-        let market_id: MarketId = self.markets.len();
-        let market = Market::new(market_id, args);
-        self.markets.push(&market);
+        self.assert_role(Role::MarketCreator);
+        self.assert_markets_migrated();
+        assert!(
+            args.trade_fee_bps <= self.config.max_trade_fee_bps,
+            "Trade fee exceeds the maximum"
+        );
+        assert!(
+            args.creator_fee_share_bps.unwrap_or(0) <= self.config.max_creator_fee_share_bps,
+            "Creator fee share exceeds the maximum"
+        );
+        assert!(
+            env::attached_deposit() >= self.config.creation_bond.0,
+            "Attached deposit doesn't cover the creation bond"
+        );
+        let collateral_decimals = self
+            .collateral_tokens
+            .get(&args.collateral_token)
+            .expect("Collateral token not registered");
+        assert!(
+            collateral_decimals >= self.config.rounding_decimals,
+            "Token has too few decimals"
+        );
+        let market_id: MarketId = self.num_markets;
+        let (market, metadata) = Market::new(market_id, args, collateral_decimals, &self.config);
+        self.market_metadata.insert(&market_id, &metadata);
+        self.save_market(&market);
+        self.num_markets += 1;
         market_id
     }
 
     pub fn get_markets(&self) -> u64 {
-        self.markets.len()
+        self.num_markets
     }
 
     fn get_market(&self, market_id: u64) -> Market {
-        self.markets.get(market_id).unwrap()
+        self.assert_markets_migrated();
+        self.markets
+            .get(&market_id)
+            .expect("Market not found")
+            .into()
+    }
+
+    fn get_market_metadata(&self, market_id: u64) -> MarketMetadata {
+        self.assert_markets_migrated();
+        self.market_metadata
+            .get(&market_id)
+            .expect("Market not found")
+    }
+
+    /// Stores `market` in the current layout
+    fn save_market(&mut self, market: &Market) {
+        let initial_storage = env::storage_usage();
+        self.markets.insert_raw(
+            &market.id.try_to_vec().unwrap(),
+            &VersionedMarket::encode(market),
+        );
+        self.market_storage_growth += env::storage_usage() as i64 - initial_storage as i64;
     }
 
+    /// Opens the market for trading. It must hold the minimum deposit of the
+    /// current config. Only the market's operator can do this.
     pub fn open_market(&mut self, market_id: MarketId) {
         let mut market = self.get_market(market_id);
-        assert_eq!(market.operator, env::signer_account_id());
+        assert_eq!(
+            market.operator,
+            env::predecessor_account_id(),
+            "Only the operator can open the market"
+        );
+        market.minimum_deposit =
+            Config::base_units(self.config.minimum_deposit, market.collateral_decimals);
         market.open();
-        self.markets.replace(market.id, &market);
+        self.save_market(&market);
+    }
+
+    /// Halts trading on the market. Only the market's operator can do this.
+    pub fn pause_market(&mut self, market_id: MarketId, reason: String) {
+        let mut market = self.get_market(market_id);
+        assert_eq!(
+            market.operator,
+            env::predecessor_account_id(),
+            "Only the operator can pause the market"
+        );
+        market.pause(reason);
+        self.save_market(&market);
+    }
+
+    /// Resumes trading on a paused market. Only the market's operator can do
+    /// this.
+    pub fn unpause_market(&mut self, market_id: MarketId, reason: String) {
+        let mut market = self.get_market(market_id);
+        assert_eq!(
+            market.operator,
+            env::predecessor_account_id(),
+            "Only the operator can unpause the market"
+        );
+        market.unpause(reason);
+        self.save_market(&market);
     }
 
-    pub fn pause_market(&mut self, market_id: MarketId) {
+    /// Settles the market. Only the market's oracle can do this, and only
+    /// once its resolution time has passed.
+    pub fn resolve_market(&mut self, market_id: MarketId, payouts: Vec<U128>) {
+        self.assert_not_paused();
         let mut market = self.get_market(market_id);
-        market.pause();
+        let payouts = payouts.into_iter().map(|p| p.into()).collect();
+        market.resolve(&env::predecessor_account_id(), payouts);
+        self.save_market(&market);
     }
 
-    pub fn resolve_market(&mut self, market_id: MarketId, payouts: Vec<u128>) {
+    /// Settles an optimistic market whose proposal was not disputed in time,
+    /// or a committee market that missed its voting deadline. Anyone can call
+    /// this.
+    pub fn finalize_resolution(&mut self, market_id: MarketId) {
+        self.assert_not_paused();
         let mut market = self.get_market(market_id);
-        assert!(market.stage == Stage::Paused || market.stage == Stage::Open);
-        assert_eq!(market.outcomes.len(), payouts.len() as u64);
+        market.finalize_pending(&env::predecessor_account_id());
+        self.save_market(&market);
+    }
 
-        // TODO(cqsd): wait for merge
-        // let expected_payout_vec_sum: u128 = 10u128.pow(market.collateral_decimals);
-        // use this as the match guard
+    /// Registers a data request for the market with its oracle contract.
+    /// Only the market's operator can do this, once the resolution time has
+    /// passed.
+    pub fn request_resolution(&mut self, market_id: MarketId) -> Promise {
+        self.assert_not_paused();
+        let mut market = self.get_market(market_id);
+        assert_eq!(
+            market.operator,
+            env::predecessor_account_id(),
+            "Only the operator can request resolution"
+        );
+        market.request_resolution();
+        self.save_market(&market);
 
-        let outcome_id = payouts
-            .iter()
-            .enumerate()
-            .max_by(|(_, value0), (_, value1)| value0.cmp(value1))
-            .map(|(idx, _)| idx)
-            .unwrap() as u32;
-        match payouts.iter().sum::<u128>() {
-            s if (s == market.collateral_decimals as u128) => {
-                // usual case, resolve the market
-                market.payouts = Some(payouts);
-                market.stage = Stage::Finalized(Finalization::Resolved { outcome_id });
+        ext_oracle::create_request(
+            U64(market_id),
+            market.num_outcomes() as u32,
+            &market.oracle,
+            0,
+            GAS_FOR_ORACLE_REQUEST,
+        )
+        .then(ext_self::on_request_created(
+            market_id,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_ORACLE_CALLBACK,
+        ))
+    }
+
+    /// Stores the ID of the registered data request, or allows the request
+    /// to be retried if the oracle contract rejected it
+    #[private]
+    pub fn on_request_created(&mut self, market_id: MarketId) {
+        assert_eq!(env::promise_results_count(), 1);
+        let request_id = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U64>(&value).ok().map(|id| id.0)
+            }
+            _ => None,
+        };
+        if request_id.is_none() {
+            log!("Oracle request for market {} failed", market_id);
+        }
+        let mut market = self.get_market(market_id);
+        market.on_request_created(request_id);
+        self.save_market(&market);
+    }
+
+    /// Receives the answer to a data request. Only the market's oracle
+    /// contract can call this.
+    pub fn set_outcome(&mut self, market_id: U64, request_id: U64, payouts: Vec<U128>) {
+        self.assert_not_paused();
+        let mut market = self.get_market(market_id.into());
+        let payouts = payouts.into_iter().map(|p| p.into()).collect();
+        market.set_outcome(&env::predecessor_account_id(), request_id.into(), payouts);
+        self.save_market(&market);
+    }
+
+    /// Casts the caller's vote as a member of the market's committee
+    pub fn submit_vote(&mut self, market_id: MarketId, payouts: Vec<U128>) {
+        self.assert_not_paused();
+        let mut market = self.get_market(market_id);
+        let payouts = payouts.into_iter().map(|p| p.into()).collect();
+        market.vote(&env::predecessor_account_id(), payouts);
+        self.save_market(&market);
+    }
+
+    /// Settles a disputed market. Only the market's arbiter can do this.
+    pub fn arbitrate(&mut self, market_id: MarketId, payouts: Vec<U128>) {
+        self.assert_not_paused();
+        let mut market = self.get_market(market_id);
+        let payouts = payouts.into_iter().map(|p| p.into()).collect();
+        market.arbitrate(&env::predecessor_account_id(), payouts);
+        self.save_market(&market);
+    }
+
+    /// Settles a market its regular resolution left unsettled for the
+    /// config's last resort delay past its resolution time. Pending bonds are
+    /// returned. Only resolvers can do this.
+    pub fn resolve_as_last_resort(&mut self, market_id: MarketId, payouts: Vec<U128>) {
+        self.assert_not_paused();
+        self.assert_role(Role::Resolver);
+        let mut market = self.get_market(market_id);
+        assert!(
+            env::block_timestamp() >= self.config.last_resort_time(market.resolution_time),
+            "Last resort delay not passed"
+        );
+        let payouts = payouts.into_iter().map(|p| p.into()).collect();
+        market.resolve_as_last_resort(&env::predecessor_account_id(), payouts);
+        self.save_market(&market);
+    }
+
+    /// Pays out the caller's returned and won resolution bonds. The balance
+    /// is restored if the transfer fails.
+    #[payable]
+    pub fn withdraw_bond(&mut self, market_id: MarketId) -> Promise {
+        assert_one_yocto();
+        let mut market = self.get_market(market_id);
+        let account_id = env::predecessor_account_id();
+
+        let amount = market.withdraw_bond(&account_id);
+        self.save_market(&market);
+
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            U128(amount),
+            Some(format!("Returning bond of {} to {}", amount, account_id)),
+            &market.collateral_token,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_bond_transfer(
+            market_id,
+            account_id,
+            U128(amount),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Restores the withdrawn bond if the collateral transfer failed
+    #[private]
+    pub fn on_bond_transfer(&mut self, market_id: MarketId, account_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "Transfer to {} failed, restoring bond of {}",
+            account_id,
+            amount.0
+        );
+        let mut market = self.get_market(market_id);
+        market.credit_bond(&account_id, amount.into());
+        self.save_market(&market);
+    }
+
+    /// Sells `num_shares` of `outcome_id` back to the market for at least
+    /// `min_amount_out` collateral. The shares are restored if the collateral
+    /// transfer fails. Resolves to the amount paid out.
+    #[payable]
+    pub fn sell_shares(
+        &mut self,
+        market_id: MarketId,
+        outcome_id: OutcomeId,
+        num_shares: u64,
+        min_amount_out: U128,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let mut market = self.get_market(market_id);
+        let seller_id = env::predecessor_account_id();
+
+        let sale = market.internal_sell(
+            &seller_id,
+            min_amount_out.into(),
+            num_shares as u128,
+            outcome_id,
+        );
+        self.save_market(&market);
+
+        ext_fungible_token::ft_transfer(
+            seller_id.clone(),
+            sale.amount,
+            Some(format!(
+                "Paying {} for {} shares to {}",
+                sale.amount.0, num_shares, seller_id
+            )),
+            &market.collateral_token,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_sell_transfer(
+            market_id,
+            sale,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Books the fee of a completed sale, or restores the seller's shares if
+    /// the collateral transfer failed
+    #[private]
+    pub fn on_sell_transfer(&mut self, market_id: MarketId, sale: PendingSale) -> U128 {
+        assert_eq!(env::promise_results_count(), 1);
+        let mut market = self.get_market(market_id);
+        let ret = match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                market.deposit_fees(sale.fee.into());
+                sale.amount
             }
-            0 => {
-                // no payouts --> the outcomes were invalid, put market in refund mode
-                market.payouts = None;
-                market.stage = Stage::Finalized(Finalization::Invalid);
-                // TODO(cqsd): need to handle the refund state in redeem?
+            _ => {
+                log!(
+                    "Transfer of {} to {} failed, restoring {} shares",
+                    sale.amount.0,
+                    sale.seller_id,
+                    sale.num_shares.0
+                );
+                market.restore_sale(&sale);
+                U128(0)
             }
-            _ => env::panic(b"Invalid payout vector"),
         };
+        self.save_market(&market);
+
+        ret
+    }
+
+    /// Burns `num_sets` complete sets of the caller and pays out one share
+    /// unit of collateral per set. The sets are restored if the transfer
+    /// fails.
+    #[payable]
+    pub fn burn_complete_sets(&mut self, market_id: MarketId, num_sets: u64) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let mut market = self.get_market(market_id);
+        let account_id = env::predecessor_account_id();
+
+        let num_sets = num_sets as u128;
+        market.burn_complete_sets(&account_id, num_sets);
+        self.save_market(&market);
+
+        let amount = num_sets.checked_mul(market.share_unit()).unwrap();
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            U128(amount),
+            Some(format!(
+                "Paying {} for {} complete sets to {}",
+                amount, num_sets, account_id
+            )),
+            &market.collateral_token,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_burn_transfer(
+            market_id,
+            account_id,
+            U128(num_sets),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Restores the burned complete sets if the collateral transfer failed
+    #[private]
+    pub fn on_burn_transfer(&mut self, market_id: MarketId, account_id: AccountId, num_sets: U128) {
+        assert_eq!(env::promise_results_count(), 1);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "Transfer to {} failed, restoring {} complete sets",
+            account_id,
+            num_sets.0
+        );
+        let mut market = self.get_market(market_id);
+        market.restore_complete_sets(&account_id, num_sets.into());
+        self.save_market(&market);
+    }
+
+    /// Burns the caller's outcome tokens of a finalized market and pays out
+    /// their value. The tokens are restored if the transfer fails.
+    #[payable]
+    pub fn redeem(&mut self, market_id: MarketId) -> Promise {
+        assert_one_yocto();
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(market_id);
+        let account_id = env::predecessor_account_id();
+
+        let redemption = market.redeem(&account_id);
+        self.save_market(&market);
+        self.internal_charge_storage(&account_id, initial_storage, false);
+
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            redemption.payout,
+            Some(format!(
+                "Redeeming outcome tokens of market {} for {} to {}",
+                market_id, redemption.payout.0, account_id
+            )),
+            &market.collateral_token,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_redeem_transfer(
+            vec![redemption],
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Redeems the caller's outcome tokens in every finalized market of
+    /// `market_ids`, or of all markets, with one transfer per collateral
+    /// token. The tokens of every market paid by a failed transfer are
+    /// restored.
+    ///
+    /// Every market redeemed takes a callback and every collateral token a
+    /// transfer out of the attached gas. Listed markets that don't fit are
+    /// rejected; a search of all markets stops at the first one that doesn't
+    /// fit, and the rest can be redeemed by calling again.
+    #[payable]
+    pub fn redeem_all(&mut self, market_ids: Option<Vec<MarketId>>) -> Promise {
+        assert_one_yocto();
+        let initial_storage = self.storage_snapshot();
+        let account_id = env::predecessor_account_id();
+        let search_all = market_ids.is_none();
+        let market_ids: Box<dyn Iterator<Item = MarketId>> = match market_ids {
+            Some(market_ids) => Box::new(market_ids.into_iter()),
+            None => Box::new(0..self.num_markets),
+        };
+
+        // redemptions grouped by collateral token
+        let mut batches: Vec<(AccountId, Vec<PendingRedemption>)> = vec![];
+        let mut scheduled_gas: Gas = 0;
+        for market_id in market_ids {
+            let mut market = self.get_market(market_id);
+            if !market.is_finalized() || market.accounts.get(&account_id).is_none() {
+                continue;
+            }
+            let new_token = batches
+                .iter()
+                .all(|(token_id, _)| *token_id != market.collateral_token);
+            let gas = if new_token {
+                GAS_FOR_RESOLVE_TRANSFER + GAS_FOR_FT_TRANSFER + GAS_FOR_SCHEDULING_TRANSFER
+            } else {
+                GAS_FOR_RESOLVE_TRANSFER
+            };
+            if env::used_gas() + scheduled_gas + gas + GAS_FOR_REDEEM_ALL > env::prepaid_gas() {
+                assert!(search_all, "Too many markets to redeem at once");
+                log!(
+                    "Out of gas at market {}, call again to redeem the rest",
+                    market_id
+                );
+                break;
+            }
+            scheduled_gas += gas;
+
+            let redemption = match market.take_redemption(&account_id) {
+                Some(redemption) => redemption,
+                None => continue,
+            };
+            self.save_market(&market);
+            if redemption.payout.0 == 0 {
+                continue;
+            }
+            match batches
+                .iter_mut()
+                .find(|(token_id, _)| *token_id == market.collateral_token)
+            {
+                Some((_, redemptions)) => redemptions.push(redemption),
+                None => batches.push((market.collateral_token, vec![redemption])),
+            }
+        }
+        self.internal_charge_storage(&account_id, initial_storage, false);
+
+        batches
+            .into_iter()
+            .map(|(token_id, redemptions)| {
+                let amount: Balance = redemptions.iter().map(|r| r.payout.0).sum();
+                let gas = GAS_FOR_RESOLVE_TRANSFER * redemptions.len() as u64;
+                ext_fungible_token::ft_transfer(
+                    account_id.clone(),
+                    U128(amount),
+                    Some(format!(
+                        "Redeeming outcome tokens of {} markets for {} to {}",
+                        redemptions.len(),
+                        amount,
+                        account_id
+                    )),
+                    &token_id,
+                    ONE_YOCTO,
+                    GAS_FOR_FT_TRANSFER,
+                )
+                .then(ext_self::on_redeem_transfer(
+                    redemptions,
+                    &env::current_account_id(),
+                    0,
+                    gas,
+                ))
+            })
+            .reduce(|all, promise| all.and(promise))
+            .expect("Nothing to redeem")
+    }
+
+    /// Restores the redeemed outcome tokens if the collateral transfer failed
+    #[private]
+    pub fn on_redeem_transfer(&mut self, redemptions: Vec<PendingRedemption>) {
+        assert_eq!(env::promise_results_count(), 1);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        let initial_storage = self.storage_snapshot();
+        let account_id = redemptions[0].account_id.clone();
+        for redemption in redemptions {
+            log!(
+                "Transfer of {} to {} failed, restoring outcome tokens of market {}",
+                redemption.payout.0,
+                redemption.account_id,
+                redemption.market_id
+            );
+            let mut market = self.get_market(redemption.market_id);
+            market.restore_redemption(&redemption);
+            self.save_market(&market);
+        }
+        self.internal_charge_storage(&account_id, initial_storage, false);
+    }
+
+    /// Burns the caller's LP shares of a finalized market and pays out their
+    /// part of the remaining subsidy and fees. The shares are restored if the
+    /// transfer fails.
+    #[payable]
+    pub fn withdraw_liquidity(&mut self, market_id: MarketId) -> Promise {
+        assert_one_yocto();
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(market_id);
+        let account_id = env::predecessor_account_id();
+
+        let (lp_shares, amount) = market.withdraw_liquidity(&account_id);
+        self.save_market(&market);
+        self.internal_charge_storage(&account_id, initial_storage, false);
+
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            U128(amount),
+            Some(format!(
+                "Paying {} for {} LP shares to {}",
+                amount, lp_shares, account_id
+            )),
+            &market.collateral_token,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_liquidity_transfer(
+            market_id,
+            account_id,
+            U128(lp_shares),
+            U128(amount),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Pays the creator part of the fees accrued so far to the market's fee
+    /// owner. The balance is restored if the transfer fails.
+    #[payable]
+    pub fn withdraw_creator_fees(&mut self, market_id: MarketId) -> Promise {
+        assert_one_yocto();
+        let market = self.get_market(market_id);
+        assert_eq!(
+            market.fee_owner,
+            env::predecessor_account_id(),
+            "Only the fee owner can withdraw creator fees"
+        );
+        let receiver_id = market.fee_owner.clone();
+        self.internal_withdraw_fees(market, FeeRecipient::Creator, receiver_id)
+    }
+
+    /// Returns the creation bond of a finalized market to its creator
+    #[payable]
+    pub fn withdraw_creation_bond(&mut self, market_id: MarketId) -> Promise {
+        assert_one_yocto();
+        let mut market = self.get_market(market_id);
+        assert!(market.is_finalized(), "Market is not finalized");
+        assert!(market.creation_bond > 0, "No creation bond to withdraw");
+        let amount = market.creation_bond;
+        market.creation_bond = 0;
+        self.save_market(&market);
+
+        Promise::new(market.creator).transfer(amount)
+    }
+
+    /// Pays the protocol part of the fees accrued so far to the config's
+    /// protocol fee recipient. The balance is restored if the transfer fails.
+    /// Only admins can do this.
+    #[payable]
+    pub fn withdraw_protocol_fees(&mut self, market_id: MarketId) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::Admin);
+        let market = self.get_market(market_id);
+        let receiver_id = self
+            .config
+            .protocol_fee_recipient
+            .clone()
+            .unwrap_or_else(|| self.owner_id.clone());
+        self.internal_withdraw_fees(market, FeeRecipient::Protocol, receiver_id)
+    }
+
+    /// Restores the withdrawn fee balance if the collateral transfer failed
+    #[private]
+    pub fn on_fee_transfer(&mut self, market_id: MarketId, recipient: FeeRecipient, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "Transfer of {:?} fees failed, restoring {}",
+            recipient,
+            amount.0
+        );
+        let mut market = self.get_market(market_id);
+        market.restore_fees(recipient, amount.into());
+        self.save_market(&market);
+    }
+
+    /// Restores the withdrawn LP shares if the collateral transfer failed
+    #[private]
+    pub fn on_liquidity_transfer(
+        &mut self,
+        market_id: MarketId,
+        account_id: AccountId,
+        lp_shares: U128,
+        amount: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), 1);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "Transfer of {} to {} failed, restoring {} LP shares",
+            amount.0,
+            account_id,
+            lp_shares.0
+        );
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(market_id);
+        market.restore_liquidity(&account_id, lp_shares.into(), amount.into());
+        self.save_market(&market);
+        self.internal_charge_storage(&account_id, initial_storage, false);
+    }
+}
 
-        self.markets.replace(market_id, &market);
+// internal methods
+impl Contract {
+    fn internal_withdraw_fees(
+        &mut self,
+        mut market: Market,
+        recipient: FeeRecipient,
+        receiver_id: AccountId,
+    ) -> Promise {
+        let amount = market.withdraw_fees(recipient);
+        self.save_market(&market);
+
+        ext_fungible_token::ft_transfer(
+            receiver_id.clone(),
+            U128(amount),
+            Some(format!(
+                "Paying {} of {:?} fees to {}",
+                amount, recipient, receiver_id
+            )),
+            &market.collateral_token,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_fee_transfer(
+            market.id,
+            recipient,
+            U128(amount),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
     }
 
-    pub fn buy(
+    pub(crate) fn buy(
         &mut self,
         sender_id: &AccountId,
         token_id: &AccountId,
@@ -106,71 +805,150 @@ impl Contract {
             token_id,
             amount
         );
-        let mut market = self.get_market(ix.market_id.into());
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(ix.market_id);
         assert_eq!(market.collateral_token, *token_id);
 
-        let ret = market.internal_buy(&sender_id, amount, ix.num_shares as u128, ix.outcome_id);
-        self.markets.replace(market.id, &market);
+        let ret = market.internal_buy(
+            sender_id,
+            amount,
+            ix.num_shares as u128,
+            ix.outcome_id,
+            ix.max_cost.map(|c| c.into()),
+            ix.valid_until,
+        );
+        self.save_market(&market);
+        self.internal_charge_storage(sender_id, initial_storage, true);
 
         ret
     }
 
-    pub fn sell(
+    pub(crate) fn buy_with_collateral(
         &mut self,
+        sender_id: &AccountId,
         token_id: &AccountId,
         amount: Balance,
-        market_id: u64,
-        outcome_id: u32,
-        num_shares: u64,
-    ) {
-        let mut market = self.get_market(market_id);
+        ix: instructions::BuyWithCollateral,
+    ) -> PromiseOrValue<U128> {
+        log!(
+            "buy_with_collateral: sender_id: {} token_id: {} amount: {}",
+            sender_id,
+            token_id,
+            amount
+        );
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(ix.market_id);
         assert_eq!(market.collateral_token, *token_id);
-        let seller_id = env::signer_account_id();
 
-        market.internal_sell(&seller_id, amount, num_shares as u128, outcome_id);
-        self.markets.replace(market.id, &market);
+        let ret = market.internal_buy_with_collateral(
+            sender_id,
+            amount,
+            ix.min_shares as u128,
+            ix.outcome_id,
+            ix.valid_until,
+        );
+        self.save_market(&market);
+        self.internal_charge_storage(sender_id, initial_storage, true);
+
+        ret
     }
 
-    pub fn deposit(
+    pub(crate) fn deposit(
         &mut self,
-        _sender_id: &AccountId,
+        sender_id: &AccountId,
         token_id: &AccountId,
         amount: Balance,
         ix: instructions::InitialDeposit,
     ) -> PromiseOrValue<U128> {
-        let mut market = self.get_market(ix.market_id.into());
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(ix.market_id);
         assert_eq!(market.collateral_token, *token_id);
-        market.deposit_collateral(amount);
+        market.deposit_collateral(sender_id, amount);
 
-        self.markets.replace(market.id, &market);
+        self.save_market(&market);
+        self.internal_charge_storage(sender_id, initial_storage, true);
 
         PromiseOrValue::Value(U128(0))
     }
 
-    pub fn withdraw_fees(&mut self, market_id: MarketId) -> Promise {
-        let mut market = self.get_market(market_id.into());
-        let ret = market.withdraw_fees();
+    pub(crate) fn mint_complete_sets(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        ix: instructions::MintCompleteSets,
+    ) -> PromiseOrValue<U128> {
+        let initial_storage = self.storage_snapshot();
+        let mut market = self.get_market(ix.market_id);
+        assert_eq!(market.collateral_token, *token_id);
+
+        let unit = market.share_unit();
+        let num_sets = amount / unit;
+        if num_sets > 0 {
+            market.mint_complete_sets(sender_id, num_sets);
+            self.save_market(&market);
+            self.internal_charge_storage(sender_id, initial_storage, true);
+        }
 
-        self.markets.replace(market.id, &market);
+        PromiseOrValue::Value(U128(amount - num_sets * unit))
+    }
 
-        ret
+    pub(crate) fn propose_resolution(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        ix: instructions::ProposeResolution,
+    ) -> PromiseOrValue<U128> {
+        let mut market = self.get_market(ix.market_id);
+        assert_eq!(market.collateral_token, *token_id);
+
+        let payouts = ix.payouts.into_iter().map(|p| p.into()).collect();
+        market.propose_resolution(sender_id, payouts, amount);
+        self.save_market(&market);
+
+        PromiseOrValue::Value(U128(0))
+    }
+
+    pub(crate) fn dispute_resolution(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+        ix: instructions::DisputeResolution,
+    ) -> PromiseOrValue<U128> {
+        let mut market = self.get_market(ix.market_id);
+        assert_eq!(market.collateral_token, *token_id);
+
+        market.dispute_resolution(sender_id, amount);
+        self.save_market(&market);
+
+        PromiseOrValue::Value(U128(0))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::instructions::{Buy, InitialDeposit, Instruction};
+    use crate::instructions::{
+        Buy, BuyWithCollateral, DisputeResolution, InitialDeposit, MintCompleteSets,
+        ProposeResolution,
+    };
+    use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
     use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
     use std::convert::TryFrom;
 
     use super::*;
+    use crate::circuit_breaker::{CircuitBreaker, WINDOW_SLOTS};
+    use crate::market_roles::MarketRole;
     use near_sdk::json_types::ValidAccountId;
+    use near_sdk::test_utils::get_created_receipts;
     use near_sdk::MockedBlockchain;
     use near_sdk::{testing_env, VMContext};
 
-    const CURRENT_ACCOUNT_ID: &'static str = "contract.testnet";
-    const SIGNER_ACCOUNT_ID: &'static str = "alice.testnet";
-    const PREDECESSOR_ACCOUNT_ID: &'static str = "alice.testnet";
+    const CURRENT_ACCOUNT_ID: &str = "contract.testnet";
+    const SIGNER_ACCOUNT_ID: &str = "alice.testnet";
+    const PREDECESSOR_ACCOUNT_ID: &str = "alice.testnet";
     const ONE_HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
 
     fn get_context(input: Vec<u8>, is_view: bool) -> VMContext {
@@ -182,7 +960,7 @@ mod tests {
             input,
             block_index: 0,
             block_timestamp: 0,
-            account_balance: 0,
+            account_balance: 10u128.pow(24),
             account_locked_balance: 0,
             storage_usage: 0,
             attached_deposit: 0,
@@ -194,6 +972,31 @@ mod tests {
         }
     }
 
+    fn new_contract() -> Contract {
+        Contract::new(
+            ValidAccountId::try_from(SIGNER_ACCOUNT_ID).unwrap(),
+            Some(Config {
+                creation_bond: U128(0),
+                ..Config::default()
+            }),
+        )
+    }
+
+    fn setup_contract() -> Contract {
+        let mut contract = new_contract();
+        contract.collateral_tokens.insert(&"test.near".into(), &9);
+        for account_id in &[SIGNER_ACCOUNT_ID, "bob.testnet"] {
+            contract.storage_accounts.insert(
+                &account_id.to_string(),
+                &StorageAccount {
+                    deposit: 10u128.pow(24),
+                    used: 0,
+                },
+            );
+        }
+        contract
+    }
+
     fn create_test_market(num_outcomes: u32) -> CreateMarketArgs {
         CreateMarketArgs {
             title: "Will Donald Trump win the 2024 US Election?".into(),
@@ -201,13 +1004,14 @@ mod tests {
                 "This question will be settled based on Associated Press (AP) election calls."
                     .into(),
             collateral_token: "test.near".into(),
-            collateral_decimals: 9,
-            trade_fee_bps: 1,
+            trade_fee_bps: 100,
+            creator_fee_share_bps: None,
             resolution_time: env::block_timestamp() + ONE_HOUR_NS,
             end_time: env::block_timestamp() + ONE_HOUR_NS,
             fee_owner: None,
             oracle: None,
             operator: None,
+            resolution_mode: None,
             outcomes: (0..num_outcomes)
                 .map(|i| Outcome {
                     id: i,
@@ -215,7 +1019,9 @@ mod tests {
                     long_name: "Test".into(),
                 })
                 .collect(),
-            liquidity: Some(50.0),
+            liquidity: Some(U128(50 * 1_000_000_000)),
+            pricing: None,
+            refund_rule: None,
         }
     }
 
@@ -223,9 +1029,7 @@ mod tests {
     fn add_market() {
         let context = get_context(vec![], false);
         testing_env!(context);
-        let mut contract = Contract {
-            markets: Vector::new(b"mk".to_vec()),
-        };
+        let mut contract = setup_contract();
         assert_eq!(0, contract.get_markets());
         let args = create_test_market(2);
         contract.create_market(args);
@@ -236,14 +1040,12 @@ mod tests {
     fn buy_shares() {
         let context = get_context(vec![], false);
         testing_env!(context);
-        let mut contract = Contract {
-            markets: Vector::new(b"mk".to_vec()),
-        };
+        let mut contract = setup_contract();
         let args = create_test_market(2);
         let market_id = contract.create_market(args);
         let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
-        let mut market = contract.markets.get(market_id).unwrap();
-        market.deposit_collateral(100_000_000_000);
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100_000_000_000);
         market.open();
         assert_eq!(None, market.outcome_balance(&account_id, 0));
         assert_eq!(None, market.outcome_balance(&account_id, 1));
@@ -256,12 +1058,10 @@ mod tests {
     fn buy_price_increase() {
         let context = get_context(vec![], false);
         testing_env!(context);
-        let mut contract = Contract {
-            markets: Vector::new(b"mk".to_vec()),
-        };
+        let mut contract = setup_contract();
         let args = create_test_market(2);
         let market_id = contract.create_market(args);
-        let market = contract.markets.get(market_id).unwrap();
+        let market = contract.get_market(market_id);
         let buy_price = market.calc_price_without_fee(0, 10, OrderDirection::Buy);
         assert!(buy_price > 5_200_000_000);
     }
@@ -270,14 +1070,12 @@ mod tests {
     fn sell_price_decrease() {
         let context = get_context(vec![], false);
         testing_env!(context);
-        let mut contract = Contract {
-            markets: Vector::new(b"mk".to_vec()),
-        };
+        let mut contract = setup_contract();
         let args = create_test_market(2);
         let market_id = contract.create_market(args);
-        let mut market = contract.markets.get(market_id).unwrap();
+        let mut market = contract.get_market(market_id);
         let account_id: AccountId = "test_account".into();
-        market.deposit_collateral(100_000_000_000);
+        market.deposit_collateral(&account_id, 100_000_000_000);
         market.open();
         market.credit(&account_id, 1, 100);
         // Selling more shares will reduce the average price
@@ -292,9 +1090,7 @@ mod tests {
     fn test_ft_on_transfer_buy() {
         let context = get_context(vec![], false);
         testing_env!(context);
-        let mut contract = Contract {
-            markets: Vector::new(b"mk".to_vec()),
-        };
+        let mut contract = setup_contract();
         let args = create_test_market(2);
         let market_id = contract.create_market(args);
         let account_id: AccountId = "alice.testnet".into();
@@ -311,19 +1107,2519 @@ mod tests {
             &token_id,
             3 * 1_000_000_000,
             Buy {
-                market_id: market_id,
+                market_id,
                 outcome_id: 0,
                 num_shares: 5,
+                max_cost: None,
+                valid_until: None,
             },
         );
         let balances = contract.get_user_balances(&account_id);
-        assert!(balances.len() > 0);
+        assert!(!balances.is_empty());
         assert_eq!(balances[0].shares, 5);
         assert_eq!(balances[0].market_id, market_id);
         assert_eq!(balances[0].outcome_id, 0);
 
-        contract.sell(&token_id, 1, market_id, 0, 1);
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.sell_shares(market_id, 0, 1, U128(1));
         let new_balances = contract.get_user_balances(&account_id);
         assert_eq!(new_balances[0].shares, 4);
     }
+
+    #[test]
+    fn sell_rollback_on_failed_transfer() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let args = create_test_market(2);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100_000_000_000);
+        market.open();
+        market.internal_buy(&account_id, 10_000_000_000, 10, 0, None, None);
+        contract.save_market(&market);
+        let base = market.calc_price_without_fee(0, 4, OrderDirection::Sell);
+        let fee = market.calc_fee(base);
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.sell_shares(market_id, 0, 4, U128(0));
+        let sold = contract.get_market(market_id);
+        assert_eq!(sold.shares, vec![6, 0]);
+        assert_eq!(sold.trading_collateral, market.trading_collateral - base);
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        context.storage_usage = env::storage_usage();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        let paid = contract.on_sell_transfer(
+            market_id,
+            PendingSale {
+                seller_id: account_id.clone(),
+                outcome_id: 0,
+                num_shares: U128(4),
+                amount: U128(base - fee),
+                fee: U128(fee),
+                num_sets: U128(0),
+            },
+        );
+        assert_eq!(paid.0, 0);
+        let restored = contract.get_market(market_id);
+        assert_eq!(restored.shares, vec![10, 0]);
+        assert_eq!(restored.outcome_balance(&account_id, 0), Some(10));
+        assert_eq!(restored.trading_collateral, market.trading_collateral);
+        assert_eq!(restored.lp_fees_accrued, market.lp_fees_accrued);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough shares to sell")]
+    fn sell_shares_beyond_balance() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        buy_as(&mut contract, market_id, SIGNER_ACCOUNT_ID);
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.sell_shares(market_id, 0, 6, U128(0));
+    }
+
+    #[test]
+    fn buy_with_collateral() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let args = create_test_market(2);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = "alice.testnet".into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &account_id,
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+
+        let amount = 3 * 1_000_000_000;
+        let before = contract.get_market(market_id);
+        let refund = match contract.buy_with_collateral(
+            &account_id,
+            &token_id,
+            amount,
+            BuyWithCollateral {
+                market_id,
+                outcome_id: 0,
+                min_shares: 1,
+                valid_until: None,
+            },
+        ) {
+            PromiseOrValue::Value(U128(refund)) => refund,
+            _ => panic!("expected a refund value"),
+        };
+        let balances = contract.get_user_balances(&account_id);
+        let shares = balances[0].shares;
+        assert!(shares > 0);
+        assert_eq!(before.calc_buy_price(0, shares), amount - refund);
+        assert!(before.calc_buy_price(0, shares + 1) > amount);
+
+        // too few shares for the budget: everything is refunded
+        let refund = match contract.buy_with_collateral(
+            &account_id,
+            &token_id,
+            amount,
+            BuyWithCollateral {
+                market_id,
+                outcome_id: 0,
+                min_shares: 1_000,
+                valid_until: None,
+            },
+        ) {
+            PromiseOrValue::Value(U128(refund)) => refund,
+            _ => panic!("expected a refund value"),
+        };
+        assert_eq!(refund, amount);
+        assert_eq!(contract.get_user_balances(&account_id)[0].shares, shares);
+    }
+
+    #[test]
+    fn buy_with_collateral_far_above_liquidity() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let account_id: AccountId = "alice.testnet".into();
+
+        // 200 times the liquidity parameter of 50 tokens
+        let amount = 10_000 * 1_000_000_000;
+        let before = contract.get_market(market_id);
+        let refund = match contract.buy_with_collateral(
+            &account_id,
+            &"test.near".into(),
+            amount,
+            BuyWithCollateral {
+                market_id,
+                outcome_id: 0,
+                min_shares: 1,
+                valid_until: None,
+            },
+        ) {
+            PromiseOrValue::Value(U128(refund)) => refund,
+            _ => panic!("expected a refund value"),
+        };
+        let shares = contract.get_user_balances(&account_id)[0].shares;
+        assert_eq!(before.calc_buy_price(0, shares), amount - refund);
+        assert!(before.calc_buy_price(0, shares + 1) > amount);
+    }
+
+    #[test]
+    fn buy_rejects_slippage_and_expiry() {
+        let mut context = get_context(vec![], false);
+        context.block_timestamp = 100;
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let args = create_test_market(2);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = "alice.testnet".into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &account_id,
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+
+        let amount = 3 * 1_000_000_000;
+        let cost = contract.get_market(market_id).calc_buy_price(0, 5);
+        let mut buy = |max_cost: Balance, valid_until: Timestamp| match contract.buy(
+            &account_id,
+            &token_id,
+            amount,
+            Buy {
+                market_id,
+                outcome_id: 0,
+                num_shares: 5,
+                max_cost: Some(U128(max_cost)),
+                valid_until: Some(valid_until),
+            },
+        ) {
+            PromiseOrValue::Value(U128(refund)) => refund,
+            _ => panic!("expected a refund value"),
+        };
+
+        assert_eq!(buy(cost - 1, ONE_HOUR_NS), amount);
+        assert_eq!(buy(cost, 99), amount);
+        assert_eq!(buy(cost, 100), amount - cost);
+        assert_eq!(contract.get_user_balances(&account_id)[0].shares, 5);
+    }
+
+    #[test]
+    fn mint_and_burn_complete_sets() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let args = create_test_market(3);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &account_id,
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+
+        let refund = match contract.mint_complete_sets(
+            &account_id,
+            &token_id,
+            5_500_000_000,
+            MintCompleteSets { market_id },
+        ) {
+            PromiseOrValue::Value(U128(refund)) => refund,
+            _ => panic!("expected a refund value"),
+        };
+        assert_eq!(refund, 500_000_000);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.complete_sets, 5);
+        assert_eq!(market.shares, vec![0, 0, 0]);
+        for outcome_id in 0..3 {
+            assert_eq!(market.outcome_balance(&account_id, outcome_id), Some(5));
+        }
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.burn_complete_sets(market_id, 2);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.complete_sets, 3);
+        assert_eq!(market.outcome_balance(&account_id, 1), Some(3));
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        context.storage_usage = env::storage_usage();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_burn_transfer(market_id, account_id.clone(), U128(2));
+        let market = contract.get_market(market_id);
+        assert_eq!(market.complete_sets, 5);
+        assert_eq!(market.outcome_balance(&account_id, 2), Some(5));
+        assert_eq!(market.shares, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn liquidity_deposits_deepen_market() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let token_id: AccountId = "test.near".into();
+        let mut market_ids = vec![];
+        for amount in &[100, 300] {
+            let market_id = contract.create_market(create_test_market(2));
+            for account_id in &[SIGNER_ACCOUNT_ID, "bob.testnet"] {
+                contract.deposit(
+                    &account_id.to_string(),
+                    &token_id,
+                    amount / 2 * 1_000_000_000,
+                    InitialDeposit { market_id },
+                );
+            }
+            contract.open_market(market_id);
+            market_ids.push(market_id);
+        }
+
+        let shallow = contract.get_market(market_ids[0]);
+        let deep = contract.get_market(market_ids[1]);
+        assert_eq!(deep.liquidity, lmsr::liquidity(300 * 1_000_000_000, 2));
+        assert!(shallow.liquidity > 50 * 1_000_000_000);
+        assert!(deep.liquidity > shallow.liquidity);
+        assert!(
+            deep.calc_buy_price(0, 10) < shallow.calc_buy_price(0, 10),
+            "the same trade costs less in the deeper market"
+        );
+    }
+
+    #[test]
+    fn liquidity_providers_share_pool() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let args = create_test_market(2);
+        let market_id = contract.create_market(args);
+        let alice: AccountId = SIGNER_ACCOUNT_ID.into();
+        let bob: AccountId = "bob.testnet".into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &alice,
+            &token_id,
+            75 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.deposit(
+            &bob,
+            &token_id,
+            25 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        assert_eq!(
+            contract.get_lp_shares(market_id, bob.clone()),
+            U128(25 * 1_000_000_000)
+        );
+        contract.open_market(market_id);
+        for outcome_id in 0..2 {
+            contract.buy(
+                &bob,
+                &token_id,
+                10 * 1_000_000_000,
+                Buy {
+                    market_id,
+                    outcome_id,
+                    num_shares: 8,
+                    max_cost: None,
+                    valid_until: None,
+                },
+            );
+        }
+
+        let mut market = contract.get_market(market_id);
+        market.payouts = Some(vec![1_000_000_000, 0]);
+        market.stage = Stage::Finalized(Finalization::Resolved { outcome_id: 0 });
+        contract.save_market(&market);
+        // everything but the 8 winning shares belongs to the LPs
+        let pool = market.deposited_collateral + market.trading_collateral + market.lp_fees_accrued
+            - 8 * 1_000_000_000;
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context.clone());
+        contract.withdraw_liquidity(market_id);
+        context.predecessor_account_id = bob.clone();
+        testing_env!(context);
+        contract.withdraw_liquidity(market_id);
+
+        let market = contract.get_market(market_id);
+        assert_eq!(market.total_lp_shares, 0);
+        assert_eq!(market.lp_pool, Some(pool - pool * 3 / 4 - pool / 4));
+        assert!(market.lp_pool.unwrap() <= 1);
+    }
+
+    #[test]
+    fn ls_lmsr_market_prices() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.pricing = Some(PricingModel::LsLmsr {
+            alpha: U128(math::ONE / 10),
+        });
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100_000_000_000);
+        market.open();
+        market.internal_buy(&account_id, 2_000_000_000_000, 1_500, 0, None, None);
+        market.internal_buy(&account_id, 2_000_000_000_000, 1_000, 1, None, None);
+        contract.save_market(&market);
+
+        // b = 0.1 * 2500 shares is above the 144 share floor the deposit
+        // funds, so prices carry vig
+        let view = contract.get_market_info(market_id);
+        let total: u128 = view.outcomes.iter().map(|o| o.price.0).sum();
+        assert!(total > math::ONE);
+        assert!(view.outcomes[0].price.0 > view.outcomes[1].price.0);
+    }
+
+    #[test]
+    fn cpmm_market_trades() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.pricing = Some(PricingModel::Cpmm);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100_000_000_000);
+        market.open();
+
+        let cost = market.calc_buy_price(0, 10);
+        market.internal_buy(&account_id, cost, 10, 0, None, None);
+        assert!(market.calculate_prices()[0] > math::ONE / 2);
+        // selling the shares straight back returns less than they cost
+        assert!(market.calc_price_without_fee(0, 10, OrderDirection::Sell) < cost);
+    }
+
+    #[test]
+    fn cpmm_market_buys_its_funding() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.pricing = Some(PricingModel::Cpmm);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100_000_000_000);
+        market.open();
+        contract.save_market(&market);
+
+        // as many shares as the pool was seeded with
+        let quote = contract.get_buy_quote(market_id, 0, 100);
+        market.internal_buy(&account_id, quote.total.0, 100, 0, None, None);
+        assert_eq!(market.shares, vec![100, 0]);
+        assert!(market.calculate_prices()[0] > math::ONE / 2);
+    }
+
+    #[test]
+    fn cpmm_buy_with_collateral_searches_near_estimate() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut args = create_test_market(3);
+        args.pricing = Some(PricingModel::Cpmm);
+        args.trade_fee_bps = 200;
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100_000_000_000);
+        market.open();
+
+        for &budget in &[1_000_000_000, 70 * 1_000_000_000, 10_000 * 1_000_000_000] {
+            let (num_shares, probes) = market.search_affordable_shares(1, budget);
+            assert!(probes <= 4, "{} prices for a budget of {}", probes, budget);
+            assert!(market.calc_buy_price(1, num_shares) <= budget);
+            assert!(market.calc_buy_price(1, num_shares + 1) > budget);
+        }
+    }
+
+    #[test]
+    fn quotes_match_execution() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.trade_fee_bps = 200;
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &account_id,
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+
+        let quote = contract.get_buy_quote(market_id, 1, 10);
+        assert_eq!(quote.total.0, quote.base_cost.0 + quote.fee.0);
+        assert_eq!(quote.average_price.0, quote.total.0 / 10);
+        assert!(quote.prices_after[1].0 > quote.prices_after[0].0);
+        assert_eq!(
+            quote.price_impact.0,
+            quote.prices_after[1].0 - math::ONE / 2
+        );
+        let refund = match contract.buy(
+            &account_id,
+            &token_id,
+            quote.total.0,
+            Buy {
+                market_id,
+                outcome_id: 1,
+                num_shares: 10,
+                max_cost: None,
+                valid_until: None,
+            },
+        ) {
+            PromiseOrValue::Value(U128(refund)) => refund,
+            _ => panic!("expected a refund value"),
+        };
+        assert_eq!(refund, 0);
+        let prices: Vec<u128> = contract
+            .get_market_info(market_id)
+            .outcomes
+            .iter()
+            .map(|o| o.price.0)
+            .collect();
+        assert_eq!(
+            prices,
+            vec![quote.prices_after[0].0, quote.prices_after[1].0]
+        );
+
+        let quote = contract.get_sell_quote(market_id, 1, 10);
+        assert_eq!(quote.total.0, quote.base_cost.0 - quote.fee.0);
+        assert_eq!(
+            quote.total.0,
+            contract.get_market(market_id).calc_sell_price(1, 10)
+        );
+        assert_eq!(quote.prices_after[0].0, math::ONE / 2);
+    }
+
+    #[test]
+    fn fees_split_between_protocol_creator_and_lps() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.trade_fee_bps = 250;
+        args.creator_fee_share_bps = Some(3_000);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &account_id,
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+
+        let market = contract.get_market(market_id);
+        let base = market.calc_price_without_fee(0, 10, OrderDirection::Buy);
+        let fee = market.calc_fee(base);
+        assert_eq!(fee, (base * 250).div_ceil(10_000));
+        contract.buy(
+            &account_id,
+            &token_id,
+            base + fee,
+            Buy {
+                market_id,
+                outcome_id: 0,
+                num_shares: 10,
+                max_cost: None,
+                valid_until: None,
+            },
+        );
+
+        let market = contract.get_market(market_id);
+        assert_eq!(market.protocol_fees_accrued, fee / 10);
+        assert_eq!(market.creator_fees_accrued, fee * 3 / 10);
+        assert_eq!(
+            market.protocol_fees_accrued + market.creator_fees_accrued + market.lp_fees_accrued,
+            fee
+        );
+
+        // a failed transfer puts the creator fees back
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context.clone());
+        contract.withdraw_creator_fees(market_id);
+        assert_eq!(contract.get_market(market_id).creator_fees_accrued, 0);
+
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        context.attached_deposit = 0;
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_fee_transfer(market_id, FeeRecipient::Creator, U128(fee * 3 / 10));
+        assert_eq!(
+            contract.get_market(market_id).creator_fees_accrued,
+            market.creator_fees_accrued
+        );
+    }
+
+    #[test]
+    fn protocol_fees_paid_to_configured_recipient() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.trade_fee_bps = 250;
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &account_id,
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        let market = contract.get_market(market_id);
+        let cost = market.calc_price_without_fee(0, 10, OrderDirection::Buy);
+        contract.buy(
+            &account_id,
+            &token_id,
+            cost + market.calc_fee(cost),
+            Buy {
+                market_id,
+                outcome_id: 0,
+                num_shares: 10,
+                max_cost: None,
+                valid_until: None,
+            },
+        );
+        assert!(contract.get_market(market_id).protocol_fees_accrued > 0);
+        contract.set_config(Config {
+            protocol_fee_recipient: Some("treasury.testnet".into()),
+            ..contract.config.clone()
+        });
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.withdraw_protocol_fees(market_id);
+        assert_eq!(contract.get_market(market_id).protocol_fees_accrued, 0);
+        let receipts = near_sdk::serde_json::to_string(&get_created_receipts()).unwrap();
+        assert!(receipts.contains("treasury.testnet"), "{}", receipts);
+    }
+
+    #[test]
+    #[should_panic(expected = "Trade fee exceeds the maximum")]
+    fn create_market_rejects_excessive_fee() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.trade_fee_bps = Config::default().max_trade_fee_bps + 1;
+        contract.create_market(args);
+    }
+
+    #[test]
+    fn rounding_decimals_follow_config() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let old_market_id = contract.create_market(create_test_market(2));
+        contract.set_config(Config {
+            rounding_decimals: 9,
+            ..contract.config.clone()
+        });
+        let market_id = contract.create_market(create_test_market(2));
+
+        // existing markets keep the precision they were created with
+        let old_market = contract.get_market(old_market_id);
+        assert_eq!(old_market.rounding_decimals, 4);
+        let cost = old_market.calc_price_without_fee(0, 10, OrderDirection::Buy);
+        assert_eq!(cost % 100_000, 0);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.rounding_decimals, 9);
+        let unrounded = market.calc_price_without_fee(0, 10, OrderDirection::Buy);
+        assert!(unrounded <= cost && cost - unrounded < 100_000);
+        assert_ne!(unrounded % 100_000, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token has too few decimals")]
+    fn create_market_rejects_token_below_rounding_decimals() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        contract.set_config(Config {
+            rounding_decimals: 10,
+            ..contract.config.clone()
+        });
+        contract.create_market(create_test_market(2));
+    }
+
+    fn open_test_market(contract: &mut Contract) -> MarketId {
+        let market_id = contract.create_market(create_test_market(2));
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        market_id
+    }
+
+    fn after_resolution_time() -> VMContext {
+        let mut context = get_context(vec![], false);
+        context.block_timestamp = ONE_HOUR_NS;
+        context.storage_usage = env::storage_usage();
+        context
+    }
+
+    #[test]
+    fn oracle_resolves_market() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+
+        let market = contract.get_market(market_id);
+        let finalization = Finalization::Resolved { outcome_id: 1 };
+        assert_eq!(market.stage, Stage::Finalized(finalization.clone()));
+        assert_eq!(market.payouts, Some(vec![0, 1_000_000_000]));
+        assert_eq!(
+            market.resolution,
+            Some(Resolution {
+                resolver: SIGNER_ACCOUNT_ID.into(),
+                timestamp: ONE_HOUR_NS,
+                finalization,
+            })
+        );
+    }
+
+    #[test]
+    fn oracle_declares_market_invalid() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(0)]);
+
+        let market = contract.get_market(market_id);
+        assert_eq!(market.stage, Stage::Finalized(Finalization::Invalid));
+        assert_eq!(market.payouts, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the oracle can resolve the market")]
+    fn resolve_rejects_non_oracle() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = "bob.testnet".into();
+        testing_env!(context);
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Resolution time not reached")]
+    fn resolve_rejects_early_resolution() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid payout vector")]
+    fn resolve_rejects_payouts_not_summing_to_one_unit() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(9)]);
+    }
+
+    fn open_optimistic_market(contract: &mut Contract) -> MarketId {
+        let mut args = create_test_market(2);
+        args.resolution_mode = Some(ResolutionMode::Optimistic {
+            arbiter: "arbiter.testnet".into(),
+            bond: U128(10 * 1_000_000_000),
+            dispute_window: ONE_HOUR_NS,
+        });
+        let market_id = contract.create_market(args);
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+
+        testing_env!(after_resolution_time());
+        contract.propose_resolution(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            10 * 1_000_000_000,
+            ProposeResolution {
+                market_id,
+                payouts: vec![U128(1_000_000_000), U128(0)],
+            },
+        );
+        market_id
+    }
+
+    #[test]
+    fn undisputed_proposal_finalizes_after_window() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_optimistic_market(&mut contract);
+        assert_eq!(contract.get_market(market_id).stage, Stage::Proposed);
+
+        let mut context = after_resolution_time();
+        context.block_timestamp = 2 * ONE_HOUR_NS;
+        context.predecessor_account_id = "anyone.testnet".into();
+        testing_env!(context);
+        contract.finalize_resolution(market_id);
+
+        let market = contract.get_market(market_id);
+        assert_eq!(
+            market.stage,
+            Stage::Finalized(Finalization::Resolved { outcome_id: 0 })
+        );
+        assert_eq!(market.resolution.unwrap().resolver, SIGNER_ACCOUNT_ID);
+        assert_eq!(
+            contract.get_bond(market_id, SIGNER_ACCOUNT_ID.into()).0,
+            10 * 1_000_000_000
+        );
+    }
+
+    #[test]
+    fn arbiter_settles_dispute_and_slashes_proposer() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_optimistic_market(&mut contract);
+        let bob: AccountId = "bob.testnet".into();
+        contract.dispute_resolution(
+            &bob,
+            &"test.near".into(),
+            20 * 1_000_000_000,
+            DisputeResolution { market_id },
+        );
+        assert_eq!(contract.get_market(market_id).stage, Stage::Disputed);
+
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = "arbiter.testnet".into();
+        testing_env!(context);
+        contract.arbitrate(market_id, vec![U128(0), U128(1_000_000_000)]);
+
+        let market = contract.get_market(market_id);
+        assert_eq!(
+            market.stage,
+            Stage::Finalized(Finalization::Resolved { outcome_id: 1 })
+        );
+        assert_eq!(market.payouts, Some(vec![0, 1_000_000_000]));
+        assert_eq!(contract.get_bond(market_id, bob).0, 30 * 1_000_000_000);
+        assert_eq!(contract.get_bond(market_id, SIGNER_ACCOUNT_ID.into()).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bond must exceed the proposal bond")]
+    fn dispute_requires_larger_bond() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_optimistic_market(&mut contract);
+        contract.dispute_resolution(
+            &"bob.testnet".into(),
+            &"test.near".into(),
+            10 * 1_000_000_000,
+            DisputeResolution { market_id },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Dispute window closed")]
+    fn dispute_rejected_after_window() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_optimistic_market(&mut contract);
+
+        let mut context = after_resolution_time();
+        context.block_timestamp = 2 * ONE_HOUR_NS;
+        testing_env!(context);
+        contract.dispute_resolution(
+            &"bob.testnet".into(),
+            &"test.near".into(),
+            20 * 1_000_000_000,
+            DisputeResolution { market_id },
+        );
+    }
+
+    fn open_committee_market(contract: &mut Contract) -> MarketId {
+        let mut args = create_test_market(2);
+        args.resolution_mode = Some(ResolutionMode::Committee {
+            members: vec!["a.testnet".into(), "b.testnet".into(), "c.testnet".into()],
+            threshold: 2,
+            deadline: 2 * ONE_HOUR_NS,
+        });
+        let market_id = contract.create_market(args);
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        market_id
+    }
+
+    #[test]
+    #[should_panic(expected = "Voting deadline must be after the resolution time")]
+    fn committee_deadline_before_resolution_time() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.resolution_mode = Some(ResolutionMode::Committee {
+            members: vec!["a.testnet".into(), "b.testnet".into()],
+            threshold: 1,
+            deadline: args.resolution_time,
+        });
+        contract.create_market(args);
+    }
+
+    fn vote_as(contract: &mut Contract, market_id: MarketId, member: &str, payouts: Vec<U128>) {
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = member.into();
+        testing_env!(context);
+        contract.submit_vote(market_id, payouts);
+    }
+
+    #[test]
+    fn committee_quorum_resolves_market() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_committee_market(&mut contract);
+
+        vote_as(
+            &mut contract,
+            market_id,
+            "a.testnet",
+            vec![U128(1_000_000_000), U128(0)],
+        );
+        vote_as(
+            &mut contract,
+            market_id,
+            "b.testnet",
+            vec![U128(0), U128(1_000_000_000)],
+        );
+        assert_eq!(contract.get_market(market_id).stage, Stage::Open);
+        assert_eq!(contract.get_votes(market_id).len(), 2);
+
+        vote_as(
+            &mut contract,
+            market_id,
+            "c.testnet",
+            vec![U128(0), U128(1_000_000_000)],
+        );
+        let market = contract.get_market(market_id);
+        assert_eq!(
+            market.stage,
+            Stage::Finalized(Finalization::Resolved { outcome_id: 1 })
+        );
+        assert_eq!(market.resolution.unwrap().resolver, "c.testnet");
+        let votes = contract.get_votes(market_id);
+        assert_eq!(votes.len(), 3);
+        assert_eq!(votes[0].member, "a.testnet");
+    }
+
+    #[test]
+    fn committee_without_quorum_becomes_invalid() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_committee_market(&mut contract);
+        vote_as(
+            &mut contract,
+            market_id,
+            "a.testnet",
+            vec![U128(1_000_000_000), U128(0)],
+        );
+
+        let mut context = after_resolution_time();
+        context.block_timestamp = 2 * ONE_HOUR_NS;
+        testing_env!(context);
+        contract.finalize_resolution(market_id);
+        assert_eq!(
+            contract.get_market(market_id).stage,
+            Stage::Finalized(Finalization::Invalid)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Member already voted")]
+    fn committee_member_votes_once() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_committee_market(&mut contract);
+        vote_as(
+            &mut contract,
+            market_id,
+            "a.testnet",
+            vec![U128(1_000_000_000), U128(0)],
+        );
+        vote_as(
+            &mut contract,
+            market_id,
+            "a.testnet",
+            vec![U128(1_000_000_000), U128(0)],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a committee member")]
+    fn committee_rejects_outsiders() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_committee_market(&mut contract);
+        vote_as(
+            &mut contract,
+            market_id,
+            "bob.testnet",
+            vec![U128(1_000_000_000), U128(0)],
+        );
+    }
+
+    fn open_external_market(contract: &mut Contract) -> MarketId {
+        let mut args = create_test_market(2);
+        args.oracle = Some("oracle.testnet".into());
+        args.resolution_mode = Some(ResolutionMode::External);
+        let market_id = contract.create_market(args);
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        market_id
+    }
+
+    fn request_created(contract: &mut Contract, market_id: MarketId, result: PromiseResult) {
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result]
+        );
+        contract.on_request_created(market_id);
+    }
+
+    #[test]
+    fn oracle_contract_callback_resolves_market() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_external_market(&mut contract);
+
+        testing_env!(after_resolution_time());
+        contract.request_resolution(market_id);
+        request_created(
+            &mut contract,
+            market_id,
+            PromiseResult::Successful(b"\"7\"".to_vec()),
+        );
+        assert_eq!(
+            contract
+                .get_market(market_id)
+                .oracle_request
+                .unwrap()
+                .request_id,
+            Some(7)
+        );
+
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = "oracle.testnet".into();
+        testing_env!(context);
+        contract.set_outcome(U64(market_id), U64(7), vec![U128(1_000_000_000), U128(0)]);
+        let market = contract.get_market(market_id);
+        assert_eq!(
+            market.stage,
+            Stage::Finalized(Finalization::Resolved { outcome_id: 0 })
+        );
+        assert_eq!(market.resolution.unwrap().resolver, "oracle.testnet");
+    }
+
+    #[test]
+    fn failed_oracle_request_can_be_retried() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_external_market(&mut contract);
+
+        testing_env!(after_resolution_time());
+        contract.request_resolution(market_id);
+        request_created(&mut contract, market_id, PromiseResult::Failed);
+        assert_eq!(contract.get_market(market_id).oracle_request, None);
+
+        testing_env!(after_resolution_time());
+        contract.request_resolution(market_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the operator can request resolution")]
+    fn request_resolution_only_by_operator() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_external_market(&mut contract);
+
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = "bob.testnet".into();
+        testing_env!(context);
+        contract.request_resolution(market_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the oracle can resolve the market")]
+    fn set_outcome_rejects_other_callers() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_external_market(&mut contract);
+
+        testing_env!(after_resolution_time());
+        contract.request_resolution(market_id);
+        request_created(
+            &mut contract,
+            market_id,
+            PromiseResult::Successful(b"\"7\"".to_vec()),
+        );
+        testing_env!(after_resolution_time());
+        contract.set_outcome(U64(market_id), U64(7), vec![U128(1_000_000_000), U128(0)]);
+    }
+
+    fn invalid_market_with_two_traders(refund_rule: RefundRule) -> (Contract, MarketId) {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.refund_rule = Some(refund_rule);
+        let market_id = contract.create_market(args);
+        let token_id: AccountId = "test.near".into();
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &token_id,
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        for (account_id, outcome_id, num_shares) in
+            [("alice.testnet", 0, 30), ("bob.testnet", 1, 10)]
+        {
+            contract.buy(
+                &account_id.into(),
+                &token_id,
+                50 * 1_000_000_000,
+                Buy {
+                    market_id,
+                    outcome_id,
+                    num_shares,
+                    max_cost: None,
+                    valid_until: None,
+                },
+            );
+        }
+
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(0)]);
+        (contract, market_id)
+    }
+
+    #[test]
+    fn invalid_market_refunds_pro_rata() {
+        let (contract, market_id) = invalid_market_with_two_traders(RefundRule::ProRata);
+        let mut market = contract.get_market(market_id);
+        let pool = market.trading_collateral;
+
+        market.redeem(&"bob.testnet".into());
+        assert_eq!(market.refund_pool, Some(pool - pool / 4));
+        assert_eq!(market.refund_units, 30);
+        market.redeem(&"alice.testnet".into());
+        assert_eq!(market.refund_pool, Some(0));
+        assert_eq!(market.refund_units, 0);
+    }
+
+    #[test]
+    fn invalid_market_refunds_cost_basis() {
+        let (contract, market_id) = invalid_market_with_two_traders(RefundRule::CostBasis);
+        let mut market = contract.get_market(market_id);
+        let pool = market.trading_collateral;
+        let bob_basis = market.cost_basis.get(&"bob.testnet".into()).unwrap();
+        assert_eq!(market.total_cost_basis, pool);
+
+        market.redeem(&"bob.testnet".into());
+        assert_eq!(market.refund_pool, Some(pool - bob_basis as u128));
+        market.redeem(&"alice.testnet".into());
+        assert_eq!(market.refund_pool, Some(0));
+        assert_eq!(market.cost_basis.get(&"alice.testnet".into()), None);
+    }
+
+    #[test]
+    fn sell_minted_leg() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        contract.mint_complete_sets(
+            &account_id,
+            &"test.near".into(),
+            10 * 1_000_000_000,
+            MintCompleteSets { market_id },
+        );
+        let quote = contract.get_sell_quote(market_id, 0, 10);
+
+        // going short on outcome 0 by selling it and keeping outcome 1
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        let proceeds = contract.sell_to_balance(market_id, 0, 10, U128(0));
+        assert_eq!(proceeds, quote.total);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.complete_sets, 0);
+        assert_eq!(market.shares, vec![0, 10]);
+        assert_eq!(market.outcome_balance(&account_id, 0), Some(0));
+        assert_eq!(market.outcome_balance(&account_id, 1), Some(10));
+        assert_eq!(
+            market.trading_collateral,
+            10 * 1_000_000_000 - quote.base_cost.0
+        );
+        assert!(market.calculate_prices()[1] > math::ONE / 2);
+    }
+
+    #[test]
+    fn failed_sale_of_minted_leg_restores_sets() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.mint_complete_sets(&account_id, 10);
+        market.internal_buy(&account_id, 10_000_000_000, 4, 0, None, None);
+        let before = market.trading_collateral;
+
+        let sale = market.internal_sell(&account_id, 0, 10, 0);
+        assert_eq!(sale.num_sets.0, 6);
+        market.restore_sale(&sale);
+        assert_eq!(market.complete_sets, 10);
+        assert_eq!(market.shares, vec![4, 0]);
+        assert_eq!(market.trading_collateral, before);
+        assert_eq!(market.outcome_balance(&account_id, 0), Some(14));
+    }
+
+    #[test]
+    fn burn_sets_bought_from_market_maker() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.internal_buy(&account_id, 10_000_000_000, 5, 0, None, None);
+        market.internal_buy(&account_id, 10_000_000_000, 5, 1, None, None);
+        let paid = market.trading_collateral;
+
+        market.burn_complete_sets(&account_id, 5);
+        assert_eq!(market.shares, vec![0, 0]);
+        assert_eq!(market.complete_sets, 0);
+        assert_eq!(market.trading_collateral, paid - 5 * 1_000_000_000);
+    }
+
+    #[test]
+    fn cost_basis_follows_trades() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.refund_rule = Some(RefundRule::CostBasis);
+        let market_id = contract.create_market(args);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let mut market = contract.get_market(market_id);
+        market.deposit_collateral(&account_id, 100 * 1_000_000_000);
+        market.open();
+
+        market.mint_complete_sets(&account_id, 3);
+        assert_eq!(market.total_cost_basis, 3 * 1_000_000_000);
+        let base = market.calc_price_without_fee(0, 10, OrderDirection::Buy);
+        let cost = market.calc_buy_price(0, 10);
+        market.internal_buy(&account_id, cost, 10, 0, None, None);
+        let sale = market.internal_sell(&account_id, 0, 10, 0);
+        market.burn_complete_sets(&account_id, 3);
+        let basis = market.cost_basis.get(&account_id).unwrap();
+        assert_eq!(basis, base as i128 - (sale.amount.0 + sale.fee.0) as i128);
+        assert_eq!(market.total_cost_basis, basis.max(0) as u128);
+    }
+
+    #[test]
+    fn redeem_burns_shares_and_rolls_back() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        contract.buy(
+            &account_id,
+            &"test.near".into(),
+            10 * 1_000_000_000,
+            Buy {
+                market_id,
+                outcome_id: 1,
+                num_shares: 8,
+                max_cost: None,
+                valid_until: None,
+            },
+        );
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+
+        let mut context = after_resolution_time();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.redeem(market_id);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.outcome_balance(&account_id, 1), None);
+        assert_eq!(market.total_redeemed, 8 * 1_000_000_000);
+
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_redeem_transfer(vec![PendingRedemption {
+            market_id,
+            account_id: account_id.clone(),
+            balances: vec![U128(0), U128(8)],
+            payout: U128(8 * 1_000_000_000),
+            refund_units: U128(0),
+            cost_basis: None,
+        }]);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.outcome_balance(&account_id, 1), Some(8));
+        assert_eq!(market.total_redeemed, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No shares to redeem")]
+    fn redeem_only_once() {
+        let (mut contract, market_id) = invalid_market_with_two_traders(RefundRule::ProRata);
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = "bob.testnet".into();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.redeem(market_id);
+        contract.redeem(market_id);
+    }
+
+    #[test]
+    fn redeem_all_batches_finalized_markets() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let market_ids: Vec<MarketId> = (0..3).map(|_| open_test_market(&mut contract)).collect();
+        for &market_id in &market_ids {
+            contract.buy(
+                &account_id,
+                &"test.near".into(),
+                10 * 1_000_000_000,
+                Buy {
+                    market_id,
+                    outcome_id: 1,
+                    num_shares: 8,
+                    max_cost: None,
+                    valid_until: None,
+                },
+            );
+        }
+        testing_env!(after_resolution_time());
+        // market 2 stays open, market 1 resolves against the holder
+        contract.resolve_market(market_ids[0], vec![U128(0), U128(1_000_000_000)]);
+        contract.resolve_market(market_ids[1], vec![U128(1_000_000_000), U128(0)]);
+
+        let mut context = after_resolution_time();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.redeem_all(None);
+
+        let won = contract.get_market(market_ids[0]);
+        assert_eq!(won.total_redeemed, 8 * 1_000_000_000);
+        assert_eq!(won.outcome_balance(&account_id, 1), None);
+        let lost = contract.get_market(market_ids[1]);
+        assert_eq!(lost.total_redeemed, 0);
+        assert_eq!(lost.outcome_balance(&account_id, 1), None);
+        let open = contract.get_market(market_ids[2]);
+        assert_eq!(open.outcome_balance(&account_id, 1), Some(8));
+    }
+
+    /// Ten markets in each of three collateral tokens, all won by the signer
+    fn won_markets_in_three_tokens() -> (Contract, Vec<MarketId>) {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut market_ids = vec![];
+        for token_id in &["a.near", "b.near", "c.near"] {
+            contract.collateral_tokens.insert(&token_id.to_string(), &9);
+            for _ in 0..10 {
+                // a fresh context per market keeps the log count in bounds
+                let mut context = get_context(vec![], false);
+                context.storage_usage = env::storage_usage();
+                testing_env!(context);
+                let mut args = create_test_market(2);
+                args.collateral_token = token_id.to_string();
+                let market_id = contract.create_market(args);
+                contract.deposit(
+                    &SIGNER_ACCOUNT_ID.into(),
+                    &token_id.to_string(),
+                    100 * 1_000_000_000,
+                    InitialDeposit { market_id },
+                );
+                contract.open_market(market_id);
+                contract.buy(
+                    &SIGNER_ACCOUNT_ID.into(),
+                    &token_id.to_string(),
+                    5 * 1_000_000_000,
+                    Buy {
+                        market_id,
+                        outcome_id: 0,
+                        num_shares: 5,
+                        max_cost: None,
+                        valid_until: None,
+                    },
+                );
+                market_ids.push(market_id);
+            }
+        }
+        for &market_id in &market_ids {
+            testing_env!(after_resolution_time());
+            contract.resolve_market(market_id, vec![U128(1_000_000_000), U128(0)]);
+        }
+        (contract, market_ids)
+    }
+
+    fn redeem_all_context() -> VMContext {
+        let mut context = after_resolution_time();
+        context.attached_deposit = 1;
+        context.prepaid_gas = 300 * 10u64.pow(12);
+        context
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many markets to redeem at once")]
+    fn redeem_all_rejects_markets_beyond_gas() {
+        let (mut contract, market_ids) = won_markets_in_three_tokens();
+        testing_env!(redeem_all_context());
+        contract.redeem_all(Some(market_ids));
+    }
+
+    #[test]
+    fn redeem_all_stops_when_out_of_gas() {
+        let (mut contract, market_ids) = won_markets_in_three_tokens();
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let redeemed = |contract: &Contract| {
+            market_ids
+                .iter()
+                .filter(|&&id| {
+                    contract
+                        .get_market(id)
+                        .outcome_balance(&account_id, 0)
+                        .is_none()
+                })
+                .count() as u64
+        };
+
+        testing_env!(redeem_all_context());
+        contract.redeem_all(None);
+        let first = redeemed(&contract);
+        // one transfer per token and one callback per market fit in the gas
+        // with the call's own share
+        let per_token = GAS_FOR_FT_TRANSFER + GAS_FOR_SCHEDULING_TRANSFER;
+        let scheduled = 3 * per_token + first * GAS_FOR_RESOLVE_TRANSFER;
+        assert!(scheduled + GAS_FOR_REDEEM_ALL <= 300 * 10u64.pow(12));
+        // the transfer of the third token no longer fits
+        assert_eq!(first, 20);
+        assert!(env::used_gas() <= 300 * 10u64.pow(12));
+
+        let mut context = redeem_all_context();
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.redeem_all(None);
+        assert_eq!(redeemed(&contract), 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral token not registered")]
+    fn deposit_rejects_unregistered_token() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = "fake.near".into();
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.ft_on_transfer(
+            ValidAccountId::try_from(SIGNER_ACCOUNT_ID).unwrap(),
+            U128(20 * 1_000_000_000),
+            r#"{"type":"Deposit"}"#.into(),
+        );
+    }
+
+    #[test]
+    fn trade_against_internal_balance() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let account_id: AccountId = SIGNER_ACCOUNT_ID.into();
+        let token_id: AccountId = "test.near".into();
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = token_id.clone();
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.ft_on_transfer(
+            ValidAccountId::try_from(SIGNER_ACCOUNT_ID).unwrap(),
+            U128(20 * 1_000_000_000),
+            r#"{"type":"Deposit"}"#.into(),
+        );
+        assert_eq!(
+            contract.get_collateral_balance(account_id.clone(), token_id.clone()),
+            U128(20 * 1_000_000_000)
+        );
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        let cost = contract.buy_from_balance(Buy {
+            market_id,
+            outcome_id: 0,
+            num_shares: 10,
+            max_cost: None,
+            valid_until: None,
+        });
+        let proceeds = contract.sell_to_balance(market_id, 0, 4, U128(0));
+        let balance = contract.get_collateral_balance(account_id.clone(), token_id.clone());
+        assert_eq!(balance.0, 20 * 1_000_000_000 - cost.0 + proceeds.0);
+        assert_eq!(
+            contract
+                .get_market(market_id)
+                .outcome_balance(&account_id, 0),
+            Some(6)
+        );
+
+        // a failed withdrawal puts the collateral back
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context.clone());
+        contract.withdraw(token_id.clone(), balance);
+        assert_eq!(
+            contract.get_collateral_balance(account_id.clone(), token_id.clone()),
+            U128(0)
+        );
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        context.attached_deposit = 0;
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_withdraw_transfer(account_id.clone(), token_id.clone(), balance);
+        assert_eq!(
+            contract.get_collateral_balance(account_id, token_id),
+            balance
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Order not executed")]
+    fn buy_from_balance_requires_funds() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.buy_from_balance(Buy {
+            market_id,
+            outcome_id: 0,
+            num_shares: 10,
+            max_cost: None,
+            valid_until: None,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn sell_to_balance_requires_one_yocto() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        contract.sell_to_balance(market_id, 0, 1, U128(0));
+    }
+
+    fn ft_metadata(decimals: u8) -> Vec<u8> {
+        serde_json::to_vec(&FungibleTokenMetadata {
+            spec: "ft-1.0.0".into(),
+            name: "Collateral".into(),
+            symbol: "COL".into(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn register_collateral_token_from_metadata() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = new_contract();
+        contract.register_collateral_token("usdc.testnet".into());
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = CURRENT_ACCOUNT_ID.into();
+        context.storage_usage = env::storage_usage();
+        testing_env!(
+            context,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(ft_metadata(6))]
+        );
+        assert_eq!(contract.on_ft_metadata("usdc.testnet".into()), 6);
+        assert_eq!(
+            contract.get_collateral_tokens(),
+            vec![("usdc.testnet".to_string(), 6)]
+        );
+
+        let mut context = get_context(vec![], false);
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        let mut args = create_test_market(2);
+        args.collateral_token = "usdc.testnet".into();
+        args.liquidity = None;
+        let market_id = contract.create_market(args);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.collateral_decimals, 6);
+        assert_eq!(market.share_unit(), 1_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Collateral token not registered")]
+    fn create_market_requires_registered_token() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = new_contract();
+        contract.create_market(create_test_market(2));
+    }
+
+    fn register_storage(contract: &mut Contract, account_id: &str, deposit: Balance) {
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = account_id.into();
+        context.attached_deposit = deposit;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.storage_deposit(None, None);
+        let mut context = get_context(vec![], false);
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+    }
+
+    fn buy_as(contract: &mut Contract, market_id: MarketId, account_id: &str) {
+        contract.buy(
+            &account_id.into(),
+            &"test.near".into(),
+            5 * 1_000_000_000,
+            Buy {
+                market_id,
+                outcome_id: 0,
+                num_shares: 5,
+                max_cost: None,
+                valid_until: None,
+            },
+        );
+    }
+
+    #[test]
+    fn storage_is_charged_for_new_positions() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let carol = ValidAccountId::try_from("carol.testnet").unwrap();
+        let deposit = 10u128.pow(24);
+        register_storage(&mut contract, "carol.testnet", deposit);
+        let min = contract.storage_balance_bounds().min.0;
+        let balance = contract.storage_balance_of(carol.clone()).unwrap();
+        assert_eq!(balance.total.0, deposit);
+        assert_eq!(balance.available.0, deposit - min);
+
+        buy_as(&mut contract, market_id, "carol.testnet");
+        let account = contract.storage_accounts.get(carol.as_ref()).unwrap();
+        assert!(account.used > 0);
+        let balance = contract.storage_balance_of(carol.clone()).unwrap();
+        assert_eq!(
+            balance.available.0,
+            deposit - min - account.used as Balance * env::storage_byte_cost()
+        );
+
+        // a second buy of the same outcome doesn't create a new entry
+        buy_as(&mut contract, market_id, "carol.testnet");
+        let used = contract.storage_accounts.get(carol.as_ref()).unwrap().used;
+        assert_eq!(used, account.used);
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = "carol.testnet".into();
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        let balance = contract.storage_withdraw(None);
+        assert_eq!(balance.available.0, 0);
+        assert_eq!(balance.total.0, account.locked());
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage deposit")]
+    fn new_position_requires_available_storage() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        let min = contract.storage_balance_bounds().min.0;
+        register_storage(&mut contract, "carol.testnet", min);
+        buy_as(&mut contract, market_id, "carol.testnet");
+    }
+
+    #[test]
+    #[should_panic(expected = "is not registered for storage")]
+    fn new_position_requires_registration() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        buy_as(&mut contract, market_id, "carol.testnet");
+    }
+
+    #[test]
+    fn unregister_refunds_deposit() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        register_storage(&mut contract, "carol.testnet", 10u128.pow(24));
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = "carol.testnet".into();
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        assert!(contract.storage_unregister(None));
+        let carol = ValidAccountId::try_from("carol.testnet").unwrap();
+        assert!(contract.storage_balance_of(carol).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister an account with open positions")]
+    fn unregister_requires_closed_positions() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        register_storage(&mut contract, "carol.testnet", 10u128.pow(24));
+        buy_as(&mut contract, market_id, "carol.testnet");
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = "carol.testnet".into();
+        context.attached_deposit = 1;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.storage_unregister(None);
+    }
+
+    fn unregister_after_redeeming(refund_rule: RefundRule) {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let mut args = create_test_market(2);
+        args.refund_rule = Some(refund_rule);
+        let market_id = contract.create_market(args);
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        register_storage(&mut contract, "carol.testnet", 10u128.pow(24));
+        buy_as(&mut contract, market_id, "carol.testnet");
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(1_000_000_000), U128(0)]);
+
+        let mut context = after_resolution_time();
+        context.predecessor_account_id = "carol.testnet".into();
+        context.attached_deposit = 1;
+        testing_env!(context.clone());
+        contract.redeem(market_id);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.outcome_balance(&"carol.testnet".into(), 0), None);
+        assert_eq!(market.cost_basis.get(&"carol.testnet".into()), None);
+
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        assert!(contract.storage_unregister(None));
+    }
+
+    #[test]
+    fn unregister_after_redeeming_pro_rata_market() {
+        unregister_after_redeeming(RefundRule::ProRata);
+    }
+
+    #[test]
+    fn unregister_after_redeeming_cost_basis_market() {
+        unregister_after_redeeming(RefundRule::CostBasis);
+    }
+
+    fn context_as(account_id: &str) -> VMContext {
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = account_id.into();
+        context.signer_account_id = account_id.into();
+        context.storage_usage = env::storage_usage();
+        context
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing role MarketCreator")]
+    fn create_market_requires_role() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        testing_env!(context_as("bob.testnet"));
+        contract.create_market(create_test_market(2));
+    }
+
+    #[test]
+    fn owner_grants_and_revokes_roles() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let bob: AccountId = "bob.testnet".into();
+        contract.grant_role(bob.clone(), Role::MarketCreator);
+        contract.grant_role(bob.clone(), Role::MarketCreator);
+        assert_eq!(contract.get_roles(bob.clone()), vec![Role::MarketCreator]);
+
+        testing_env!(context_as("bob.testnet"));
+        let market_id = contract.create_market(create_test_market(2));
+        assert_eq!(contract.get_market(market_id).creator, bob);
+
+        testing_env!(context_as(SIGNER_ACCOUNT_ID));
+        contract.revoke_role(bob.clone(), Role::MarketCreator);
+        assert!(contract.get_roles(bob.clone()).is_empty());
+        assert!(!contract.has_role(&bob, Role::MarketCreator));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can do this")]
+    fn set_config_requires_owner() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        testing_env!(context_as("bob.testnet"));
+        contract.set_config(Config::default());
+    }
+
+    #[test]
+    fn markets_read_the_config() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        contract.set_config(Config {
+            default_liquidity: U128(20),
+            minimum_deposit: U128(150),
+            creation_bond: U128(0),
+            ..Config::default()
+        });
+        let mut args = create_test_market(2);
+        args.liquidity = None;
+        let market_id = contract.create_market(args);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.liquidity, 20 * 1_000_000_000);
+        assert_eq!(market.minimum_deposit, 150 * 1_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit doesn't cover the creation bond")]
+    fn create_market_requires_creation_bond() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        contract.set_config(Config::default());
+        contract.create_market(create_test_market(2));
+    }
+
+    #[test]
+    fn creation_bond_is_returned_after_finalization() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        contract.set_config(Config::default());
+        let bond = Config::default().creation_bond.0;
+
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = bond;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        let market_id = open_test_market(&mut contract);
+        assert_eq!(contract.get_market(market_id).creation_bond, bond);
+
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+        let mut context = after_resolution_time();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.withdraw_creation_bond(market_id);
+        assert_eq!(contract.get_market(market_id).creation_bond, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn withdraw_creation_bond_requires_one_yocto() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+        contract.withdraw_creation_bond(market_id);
+    }
+
+    #[test]
+    fn resolver_settles_stuck_market_and_returns_bonds() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        contract.grant_role("resolver.testnet".into(), Role::Resolver);
+        let market_id = open_optimistic_market(&mut contract);
+        let bob: AccountId = "bob.testnet".into();
+        contract.dispute_resolution(
+            &bob,
+            &"test.near".into(),
+            20 * 1_000_000_000,
+            DisputeResolution { market_id },
+        );
+
+        let mut context = context_as("resolver.testnet");
+        context.block_timestamp = ONE_HOUR_NS + Config::default().last_resort_delay.0;
+        testing_env!(context);
+        contract.resolve_as_last_resort(market_id, vec![U128(0), U128(0)]);
+
+        let market = contract.get_market(market_id);
+        assert_eq!(market.stage, Stage::Finalized(Finalization::Invalid));
+        assert_eq!(contract.get_bond(market_id, bob).0, 20 * 1_000_000_000);
+        assert_eq!(
+            contract.get_bond(market_id, SIGNER_ACCOUNT_ID.into()).0,
+            10 * 1_000_000_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Last resort delay not passed")]
+    fn last_resort_waits_for_delay() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        testing_env!(after_resolution_time());
+        contract.resolve_as_last_resort(market_id, vec![U128(0), U128(1_000_000_000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn global_pause_blocks_trading() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        contract.set_paused(true);
+        assert!(contract.is_paused());
+
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = "test.near".into();
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        contract.ft_on_transfer(
+            ValidAccountId::try_from(SIGNER_ACCOUNT_ID).unwrap(),
+            U128(3 * 1_000_000_000),
+            format!(
+                r#"{{"type":"Buy","market_id":{},"outcome_id":0,"num_shares":5}}"#,
+                market_id
+            ),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn global_pause_blocks_resolution() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        contract.set_paused(true);
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(0), U128(1_000_000_000)]);
+    }
+
+    #[test]
+    fn global_pause_allows_settled_withdrawals() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        buy_as(&mut contract, market_id, SIGNER_ACCOUNT_ID);
+        testing_env!(after_resolution_time());
+        contract.resolve_market(market_id, vec![U128(1_000_000_000), U128(0)]);
+        contract.set_paused(true);
+
+        let mut context = after_resolution_time();
+        context.attached_deposit = 1;
+        testing_env!(context);
+        contract.redeem(market_id);
+        contract.withdraw_liquidity(market_id);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.total_redeemed, 5 * 1_000_000_000);
+    }
+
+    fn open_guarded_market(contract: &mut Contract) -> MarketId {
+        contract.set_config(Config {
+            creation_bond: U128(0),
+            minimum_deposit: U128(0),
+            circuit_breaker: Some(CircuitBreaker {
+                max_trade_move: U128(5 * 10u128.pow(16)),
+                max_window_move: U128(8 * 10u128.pow(16)),
+                window: U64(ONE_HOUR_NS / 6),
+            }),
+            ..Config::default()
+        });
+        // just the subsidy of the default 50 token liquidity
+        let market_id = contract.create_market(create_test_market(2));
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            lmsr::fund(50 * 1_000_000_000, 2),
+            InitialDeposit { market_id },
+        );
+        contract.open_market(market_id);
+        market_id
+    }
+
+    #[test]
+    fn circuit_breaker_trips_on_large_trade() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_guarded_market(&mut contract);
+        contract.buy(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            20 * 1_000_000_000,
+            Buy {
+                market_id,
+                outcome_id: 0,
+                num_shares: 20,
+                max_cost: None,
+                valid_until: None,
+            },
+        );
+        let market = contract.get_market(market_id);
+        // the trade that tripped the breaker stands
+        assert_eq!(market.shares, vec![20, 0]);
+        assert_eq!(market.stage, Stage::Paused);
+
+        contract.open_market(market_id);
+        assert_eq!(contract.get_market(market_id).stage, Stage::Open);
+    }
+
+    #[test]
+    #[should_panic(expected = "Market is not open for trading")]
+    fn circuit_breaker_rejects_trades_after_tripping() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_guarded_market(&mut contract);
+        let buy = |num_shares| Buy {
+            market_id,
+            outcome_id: 0,
+            num_shares,
+            max_cost: None,
+            valid_until: None,
+        };
+        let token_id: AccountId = "test.near".into();
+        contract.buy(
+            &SIGNER_ACCOUNT_ID.into(),
+            &token_id,
+            20 * 1_000_000_000,
+            buy(20),
+        );
+        assert_eq!(
+            contract
+                .get_market(market_id)
+                .outcome_balance(&SIGNER_ACCOUNT_ID.into(), 0),
+            Some(20)
+        );
+
+        contract.buy(&"bob.testnet".into(), &token_id, 1_000_000_000, buy(1));
+    }
+
+    #[test]
+    fn circuit_breaker_trips_on_window_move() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_guarded_market(&mut contract);
+        for _ in 0..3 {
+            buy_as(&mut contract, market_id, SIGNER_ACCOUNT_ID);
+            assert_eq!(contract.get_market(market_id).stage, Stage::Open);
+        }
+
+        // trades older than the window no longer count
+        let mut context = get_context(vec![], false);
+        context.block_timestamp = ONE_HOUR_NS / 6;
+        context.storage_usage = env::storage_usage();
+        testing_env!(context);
+        for _ in 0..3 {
+            buy_as(&mut contract, market_id, SIGNER_ACCOUNT_ID);
+            assert_eq!(contract.get_market(market_id).stage, Stage::Open);
+        }
+        buy_as(&mut contract, market_id, SIGNER_ACCOUNT_ID);
+        assert_eq!(contract.get_market(market_id).stage, Stage::Paused);
+    }
+
+    #[test]
+    fn circuit_breaker_window_rolls() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_guarded_market(&mut contract);
+        let trade_at = |contract: &mut Contract, block_timestamp| {
+            let mut context = get_context(vec![], false);
+            context.block_timestamp = block_timestamp;
+            context.storage_usage = env::storage_usage();
+            testing_env!(context);
+            buy_as(contract, market_id, SIGNER_ACCOUNT_ID);
+        };
+        trade_at(&mut contract, 0);
+        trade_at(&mut contract, ONE_HOUR_NS / 6 - 1);
+        trade_at(&mut contract, ONE_HOUR_NS / 6 - 1);
+        trade_at(&mut contract, ONE_HOUR_NS / 6);
+        assert_eq!(contract.get_market(market_id).stage, Stage::Open);
+
+        // the move since the second trade is within one window, even though
+        // a window that started with the first trade has ended
+        trade_at(&mut contract, ONE_HOUR_NS / 6);
+        assert_eq!(contract.get_market(market_id).stage, Stage::Paused);
+    }
+
+    #[test]
+    fn circuit_breaker_window_stays_bounded() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_guarded_market(&mut contract);
+        let record_len = VersionedMarket::encode(&contract.get_market(market_id)).len();
+        // three slots apart, so at most three trades are ever in the window
+        for trade in 0..12 {
+            let mut context = get_context(vec![], false);
+            context.block_timestamp = trade * 3 * (ONE_HOUR_NS / 6 / WINDOW_SLOTS);
+            context.storage_usage = env::storage_usage();
+            testing_env!(context);
+            buy_as(&mut contract, market_id, SIGNER_ACCOUNT_ID);
+        }
+        let market = contract.get_market(market_id);
+        assert_eq!(market.stage, Stage::Open);
+        let window = market.price_window.as_ref().unwrap();
+        assert_eq!(window.slots.len() as u64, WINDOW_SLOTS);
+        assert_eq!(VersionedMarket::encode(&market).len(), record_len);
+    }
+
+    #[test]
+    fn circuit_breaker_pause_is_not_charged_to_trader() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_guarded_market(&mut contract);
+        register_storage(&mut contract, "erin.testnet", 10u128.pow(24));
+        register_storage(&mut contract, "dave.testnet", 10u128.pow(24));
+        buy_as(&mut contract, market_id, "erin.testnet");
+        contract.buy(
+            &"dave.testnet".into(),
+            &"test.near".into(),
+            20 * 1_000_000_000,
+            Buy {
+                market_id,
+                outcome_id: 0,
+                num_shares: 20,
+                max_cost: None,
+                valid_until: None,
+            },
+        );
+        assert_eq!(contract.get_market(market_id).stage, Stage::Paused);
+
+        // the pause reason grew the market record, but dave pays only for
+        // their own position, like erin
+        let used = |account_id: &str| {
+            contract
+                .storage_accounts
+                .get(&account_id.into())
+                .unwrap()
+                .used
+        };
+        assert_eq!(used("dave.testnet"), used("erin.testnet"));
+    }
+
+    #[test]
+    fn operator_pauses_and_unpauses_market() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        contract.pause_market(market_id, "Investigating a price feed".into());
+        let market = contract.get_market(market_id);
+        assert_eq!(market.stage, Stage::Paused);
+        assert_eq!(
+            market.pause_reason,
+            Some("Investigating a price feed".to_string())
+        );
+
+        contract.unpause_market(market_id, "Price feed fixed".into());
+        let market = contract.get_market(market_id);
+        assert_eq!(market.stage, Stage::Open);
+        assert_eq!(market.pause_reason, None);
+    }
+
+    #[test]
+    fn market_roles_follow_predecessor() {
+        let mut context = get_context(vec![], false);
+        context.signer_account_id = "bob.testnet".into();
+        testing_env!(context);
+        let mut contract = setup_contract();
+        let market_id = contract.create_market(create_test_market(2));
+        let market = contract.get_market(market_id);
+        assert_eq!(market.creator, SIGNER_ACCOUNT_ID);
+        assert_eq!(market.operator, SIGNER_ACCOUNT_ID);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the operator can open the market")]
+    fn open_market_requires_operator() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = contract.create_market(create_test_market(2));
+        contract.deposit(
+            &SIGNER_ACCOUNT_ID.into(),
+            &"test.near".into(),
+            100 * 1_000_000_000,
+            InitialDeposit { market_id },
+        );
+
+        // signed by the operator, but relayed through another contract
+        let mut context = context_as("relay.testnet");
+        context.signer_account_id = SIGNER_ACCOUNT_ID.into();
+        testing_env!(context);
+        contract.open_market(market_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the operator can pause the market")]
+    fn pause_market_requires_operator() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        testing_env!(context_as("bob.testnet"));
+        contract.pause_market(market_id, "Not mine to pause".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "A reason is required")]
+    fn pause_market_requires_reason() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = open_test_market(&mut contract);
+        contract.pause_market(market_id, " ".into());
+    }
+
+    #[test]
+    fn market_roles_transfer_in_two_steps() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = contract.create_market(create_test_market(2));
+        let bob: AccountId = "bob.testnet".into();
+        contract.propose_role_transfer(market_id, MarketRole::Oracle, "carol.testnet".into());
+        contract.propose_role_transfer(market_id, MarketRole::Oracle, bob.clone());
+        contract.propose_role_transfer(market_id, MarketRole::FeeOwner, bob.clone());
+        assert_eq!(contract.get_market(market_id).role_transfers.len(), 2);
+        assert_eq!(contract.get_market(market_id).oracle, SIGNER_ACCOUNT_ID);
+
+        testing_env!(context_as("bob.testnet"));
+        contract.accept_role_transfer(market_id, MarketRole::Oracle);
+        let market = contract.get_market(market_id);
+        assert_eq!(market.oracle, bob);
+        assert_eq!(market.fee_owner, SIGNER_ACCOUNT_ID);
+
+        testing_env!(context_as(SIGNER_ACCOUNT_ID));
+        contract.cancel_role_transfer(market_id, MarketRole::FeeOwner);
+        assert!(contract.get_market(market_id).role_transfers.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "No role transfer to accept")]
+    fn role_transfer_accepted_only_by_proposed_account() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = contract.create_market(create_test_market(2));
+        contract.propose_role_transfer(market_id, MarketRole::Operator, "bob.testnet".into());
+        testing_env!(context_as("carol.testnet"));
+        contract.accept_role_transfer(market_id, MarketRole::Operator);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the Operator can do this")]
+    fn role_transfer_proposed_only_by_holder() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let market_id = contract.create_market(create_test_market(2));
+        testing_env!(context_as("bob.testnet"));
+        contract.propose_role_transfer(market_id, MarketRole::Operator, "bob.testnet".into());
+    }
+
+    /// A market as the first release stored it: liquidity 50 and a deposit
+    /// of 100 tokens with 9 decimals, then a purchase of 5 shares of outcome 0
+    /// by alice. Writes the outcomes and balances, whose layout is unchanged.
+    fn first_release_market(id: u64) -> migration::MarketV1 {
+        let mut outcomes = Vector::new(format!("outcomes{}", id).as_bytes().to_vec());
+        outcomes.extend((0..2).map(|i| Outcome {
+            id: i,
+            short_name: format!("S{}", i),
+            long_name: format!("L{}", i),
+        }));
+        let mut accounts = LookupMap::new(format!("accmap{}", id).as_bytes().to_vec());
+        accounts.insert(&SIGNER_ACCOUNT_ID.to_string(), &vec![5, 0]);
+        migration::MarketV1 {
+            id,
+            title: "Baseline".into(),
+            description: "Stored by the first release".into(),
+            collateral_token: "test.near".into(),
+            collateral_decimals: 9,
+            deposited_collateral: 100_000_000_000,
+            minimum_deposit: 100_000_000_000,
+            end_time: ONE_HOUR_NS,
+            resolution_time: ONE_HOUR_NS,
+            outcomes,
+            liquidity: 50.0,
+            shares: vec![5.0, 0.0],
+            payouts: None,
+            oracle: SIGNER_ACCOUNT_ID.into(),
+            operator: SIGNER_ACCOUNT_ID.into(),
+            stage: migration::StageV1::Open,
+            fee_owner: SIGNER_ACCOUNT_ID.into(),
+            trade_fee_bps: 1,
+            fees_accrued: 25_625_000,
+            volume: 0,
+            accounts,
+        }
+    }
+
+    /// Contract record and `first_release_market(0)` as encoded by the code of
+    /// the first release
+    const FIRST_RELEASE_STATE: &str = "01000000000000000f0000006e6561722d70726564696374696f6e";
+    const FIRST_RELEASE_MARKET: &str = concat!(
+        "000000000000000008000000426173656c696e651b00000053746f72656420627920",
+        "7468652066697273742072656c6561736509000000746573742e6e65617209000000",
+        "00e8764817000000000000000000000000e8764817000000000000000000000000a0",
+        "b8304603000000a0b830460300000200000000000000090000006f7574636f6d6573",
+        "3000000000000049400200000000000000000014400000000000000000000d000000",
+        "616c6963652e746573746e65740d000000616c6963652e746573746e6574010d0000",
+        "00616c6963652e746573746e65740100a80187010000000000000000000000000000",
+        "0000000000000000000000000000070000006163636d617030",
+    );
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn first_release_state(num_markets: u64) {
+        let mut markets = Vector::new(b"near-prediction".to_vec());
+        for id in 0..num_markets {
+            markets.push(&first_release_market(id));
+        }
+        env::state_write(&migration::ContractV1 { markets });
+    }
+
+    fn migrate_as_contract() -> Contract {
+        testing_env!(context_as(CURRENT_ACCOUNT_ID));
+        let contract =
+            Contract::migrate(ValidAccountId::try_from(SIGNER_ACCOUNT_ID).unwrap(), None);
+        testing_env!(context_as(SIGNER_ACCOUNT_ID));
+        contract
+    }
+
+    #[test]
+    fn first_release_layout_matches() {
+        testing_env!(get_context(vec![], false));
+        assert_eq!(
+            first_release_market(0).try_to_vec().unwrap(),
+            from_hex(FIRST_RELEASE_MARKET)
+        );
+    }
+
+    #[test]
+    fn migrate_first_release_state() {
+        testing_env!(get_context(vec![], false));
+        env::storage_write(b"STATE", &from_hex(FIRST_RELEASE_STATE));
+        env::storage_write(
+            &[b"near-prediction".as_ref(), &0u64.to_le_bytes()].concat(),
+            &from_hex(FIRST_RELEASE_MARKET),
+        );
+        first_release_market(0);
+
+        let mut contract = migrate_as_contract();
+        assert_eq!(contract.get_state_version(), STATE_VERSION);
+        assert_eq!(contract.get_owner(), SIGNER_ACCOUNT_ID);
+        assert_eq!(contract.migrate_markets(10), 0);
+        assert_eq!(contract.get_markets(), 1);
+        assert_eq!(
+            contract.get_collateral_tokens(),
+            vec![("test.near".into(), 9)]
+        );
+
+        let market = contract.get_market(0);
+        assert_eq!(market.stage, Stage::Open);
+        assert_eq!(market.liquidity, 50_000_000_000);
+        assert_eq!(market.rounding_decimals, 4);
+        assert_eq!(market.shares, vec![5, 0]);
+        assert_eq!(market.creator_fees_accrued, 25_625_000);
+        assert_eq!(
+            market.lp_shares.get(&SIGNER_ACCOUNT_ID.to_string()),
+            Some(100_000_000_000)
+        );
+        // the first release charged 2.5625 tokens for the 5 shares
+        assert!((market.trading_collateral as i128 - 2_562_500_000).abs() < 100_000);
+        assert!(market.calculate_prices()[0] > math::ONE / 2);
+
+        let view = contract.get_market_info(0);
+        assert_eq!(view.title, "Baseline");
+        assert_eq!(view.outcomes[1].long_name, "L1");
+        let balances = contract.get_user_balances(&SIGNER_ACCOUNT_ID.to_string());
+        assert_eq!(balances[0].shares, 5);
+
+        // the outcomes moved into the metadata record
+        assert!(
+            env::storage_read(&[b"outcomes0".as_ref(), &0u64.to_le_bytes()].concat()).is_none()
+        );
+        let record =
+            env::storage_read(&[b"markets".as_ref(), &0u64.try_to_vec().unwrap()].concat())
+                .unwrap();
+        assert!(record.len() < 1_000);
+        // stored as the current version, and encoded back the same
+        let stored: Market = VersionedMarket::try_from_slice(&record).unwrap().into();
+        assert_eq!(stored.shares, market.shares);
+        assert_eq!(VersionedMarket::encode(&stored), record);
+    }
+
+    #[test]
+    fn migrate_rescales_first_release_payouts() {
+        testing_env!(get_context(vec![], false));
+        let mut market = first_release_market(0);
+        market.stage = migration::StageV1::Finalized(Finalization::Resolved { outcome_id: 0 });
+        // the first release summed payouts to the collateral decimals
+        market.payouts = Some(vec![9, 0]);
+        let (market, _) = market.upgrade();
+        assert_eq!(market.payouts, Some(vec![1_000_000_000, 0]));
+        assert_eq!(
+            market.stage,
+            Stage::Finalized(Finalization::Resolved { outcome_id: 0 })
+        );
+    }
+
+    #[test]
+    fn migrate_markets_in_pages() {
+        testing_env!(get_context(vec![], false));
+        first_release_state(3);
+
+        let mut contract = migrate_as_contract();
+        assert_eq!(contract.migrate_markets(2), 1);
+        assert_eq!(contract.migrate_markets(2), 0);
+        assert_eq!(contract.get_markets(), 3);
+        for id in 0..3 {
+            assert_eq!(contract.get_market(id).id, id);
+            assert_eq!(contract.get_market_info(id).outcomes.len(), 2);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Markets are being migrated")]
+    fn markets_unavailable_until_migrated() {
+        testing_env!(get_context(vec![], false));
+        first_release_state(3);
+
+        let mut contract = migrate_as_contract();
+        contract.migrate_markets(2);
+        contract.get_market_info(0);
+    }
+
+    #[test]
+    fn trading_record_excludes_description() {
+        testing_env!(get_context(vec![], false));
+        let mut contract = setup_contract();
+        let short = contract.create_market(create_test_market(2));
+        let mut args = create_test_market(2);
+        args.description = "A long description".repeat(100);
+        let long = contract.create_market(args);
+
+        let record = |id: MarketId| {
+            env::storage_read(&[b"markets".as_ref(), &id.try_to_vec().unwrap()].concat())
+                .unwrap()
+                .len()
+        };
+        assert_eq!(record(short), record(long));
+        assert_eq!(
+            contract.get_market_info(long).description.len(),
+            "A long description".len() * 100
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the contract account can migrate")]
+    fn migrate_requires_contract_account() {
+        testing_env!(get_context(vec![], false));
+        first_release_state(1);
+        testing_env!(context_as(SIGNER_ACCOUNT_ID));
+        Contract::migrate(ValidAccountId::try_from(SIGNER_ACCOUNT_ID).unwrap(), None);
+    }
 }
@@ -1,120 +1,224 @@
-pub fn fund(liquidity: f64, n: usize) -> f64 {
-    liquidity * (n as f64).ln()
+//! Logarithmic market scoring rule in deterministic fixed point.
+//!
+//! `liquidity` and `volumes` are expressed in one common unit (collateral
+//! base units in `Market`) and every result is returned in that same unit.
+//! Costs are accurate to within [`cost_error`]; quotes from [`estimate`] are
+//! padded by that bound and rounded against the trader, so the market maker
+//! never loses more than its subsidy to rounding.
+
+use std::convert::TryFrom;
+
+use crate::math::{self, ONE};
+
+/// Subsidy required for liquidity parameter `liquidity` over `n` outcomes,
+/// rounded up
+pub fn fund(liquidity: u128, n: usize) -> u128 {
+    let ln_n = math::ln(n as u128 * ONE) + math::LN_ERROR;
+    math::mul_div_ceil(liquidity, ln_n, ONE)
 }
 
-pub fn liquidity(fund: f64, n: usize) -> f64 {
-    fund / (n as f64).ln()
+/// Largest liquidity parameter that `fund` can subsidize over `n` outcomes
+pub fn liquidity(fund: u128, n: usize) -> u128 {
+    let ln_n = math::ln(n as u128 * ONE) + math::LN_ERROR;
+    math::mul_div(fund, ONE, ln_n)
 }
 
-fn coefficient(liquidity: f64, volumes: &[f64]) -> (Vec<f64>, f64) {
-    let mut max: f64 = 0.0;
+/// Returns e^((v_i - max) / liquidity) for every volume, in fixed point,
+/// together with the largest volume
+pub(crate) fn coefficient(liquidity: u128, volumes: &[u128]) -> (Vec<u128>, u128) {
+    let max = volumes.iter().copied().max().unwrap_or(0);
 
     let ret = volumes
         .iter()
-        .map(|v| {
-            let tmp = v / liquidity;
-            if tmp > max {
-                max = tmp;
-            }
-            tmp
-        })
+        .map(|&v| math::exp_neg(math::mul_div(max - v, ONE, liquidity)))
         .collect::<Vec<_>>();
 
     (ret, max)
 }
 
-fn shift_exp_sum(max: f64, data: &[f64]) -> f64 {
-    data.iter().fold(0.0, |acc, &v| acc + (v - max).exp())
+fn shift_exp_sum(data: &[u128]) -> u128 {
+    data.iter().sum()
 }
 
-fn ln_sum(liquidity: f64, volumes: &[f64]) -> f64 {
-    let (tmp, max) = coefficient(liquidity, volumes);
-
-    let sum = shift_exp_sum(max, &tmp);
-    sum.ln() + max
+/// Upper bound on the absolute error of [`cost`] for `n` outcomes
+pub fn cost_error(liquidity: u128, n: usize) -> u128 {
+    let ln_sum_error = n as u128 * (math::EXP_NEG_ERROR + 1) + math::LN_ERROR;
+    math::mul_div_ceil(liquidity, ln_sum_error, ONE) + 1
 }
 
-pub fn cost(liquidity: f64, volumes: &[f64]) -> f64 {
-    liquidity * ln_sum(liquidity, volumes)
+/// C(q) = b * ln(sum(e^(q_i / b))), computed as max + b * ln(sum(e^((q_i - max) / b)))
+pub fn cost(liquidity: u128, volumes: &[u128]) -> u128 {
+    let (tmp, max) = coefficient(liquidity, volumes);
+    let sum = shift_exp_sum(&tmp);
+
+    max + math::mul_div(liquidity, math::ln(sum), ONE)
 }
 
-pub fn compute_price(liquidity: f64, volumes: &[f64]) -> Vec<f64> {
-    let (tmp, max) = coefficient(liquidity, volumes);
-    let sum = shift_exp_sum(max, &tmp);
+/// Marginal prices, in fixed point. They sum to at most `ONE`.
+pub fn compute_price(liquidity: u128, volumes: &[u128]) -> Vec<u128> {
+    let (tmp, _) = coefficient(liquidity, volumes);
+    let sum = shift_exp_sum(&tmp);
 
     tmp.iter()
-        .map(|v| (v - max - sum.ln()).exp())
+        .map(|&v| math::mul_div(v, ONE, sum))
         .collect::<Vec<_>>()
 }
 
-pub fn estimate(liquidity: f64, origin: &[f64], index: usize, amount: f64) -> f64 {
+/// Cost of changing the volume at `index` by `amount` (negative to sell).
+/// The result is rounded towards positive infinity: buyers never pay less
+/// and sellers never receive more than the exact LMSR cost.
+pub fn estimate(liquidity: u128, origin: &[u128], index: usize, amount: i128) -> i128 {
     let mut after = Vec::from(origin);
-    after[index] += amount;
-    cost(liquidity, &after) - cost(liquidity, origin)
+    after[index] = if amount >= 0 {
+        after[index].checked_add(amount as u128).unwrap()
+    } else {
+        after[index].checked_sub(amount.unsigned_abs()).unwrap()
+    };
+    let before = i128::try_from(cost(liquidity, origin)).unwrap();
+    let after = i128::try_from(cost(liquidity, &after)).unwrap();
+    let margin = i128::try_from(cost_error(liquidity, origin.len())).unwrap();
+
+    after - before + 2 * margin
 }
 
-pub fn volume(liquidity: f64, origin: &[f64], index: usize, amount: f64) -> f64 {
-    let a = (amount / liquidity).exp() - 1.0;
+/// Volume at `index` that `amount` buys, i.e. the inverse of [`estimate`] for
+/// purchases. The result is approximate: callers that must stay within a
+/// budget should confirm it with [`estimate`].
+pub fn volume(liquidity: u128, origin: &[u128], index: usize, amount: u128) -> u128 {
+    // After buying d: sum' = sum * e^(a/b) = sum - w_i + w_i * e^(d/b), so
+    // d = (max - q_i) + a + b * ln(sum - (sum - w_i) * e^(-a/b)). Only e^-x
+    // appears, so any budget works: past the cutoff it is zero anyway.
+    let x = if amount / liquidity >= math::EXP_NEG_CUTOFF / ONE {
+        math::EXP_NEG_CUTOFF
+    } else {
+        math::mul_div(amount, ONE, liquidity)
+    };
 
     let (tmp, max) = coefficient(liquidity, origin);
-    let sum = shift_exp_sum(max, &tmp);
-
-    let p = (tmp[index] - max - sum.ln()).exp();
+    let sum = shift_exp_sum(&tmp);
 
-    liquidity * (a / p + 1.0).ln()
+    let y = sum - math::mul_div(sum - tmp[index], math::exp_neg(x), ONE);
+    if y == 0 {
+        return 0;
+    }
+    let ln_y = math::ln_signed(y);
+    let scaled = math::mul_div(liquidity, ln_y.unsigned_abs(), ONE);
+    let offset = (max - origin[index]).checked_add(amount).unwrap();
+
+    if ln_y >= 0 {
+        offset.checked_add(scaled).unwrap()
+    } else {
+        offset.saturating_sub(scaled)
+    }
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     const FUND: f64 = 69.314_718_055_994_53;
-    const LIQ: f64 = 100.0;
+    const LIQ: u128 = 100 * ONE;
+
+    fn to_f64(x: u128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= 1e-12 * expected.abs().max(1.0),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
 
     fn test_round(
-        vols: &[f64],
+        vols: &[u128],
         index: usize,
         add: f64,
-        old_cost: f64,
+        old_cost: u128,
         ans: &[f64],
-    ) -> (Vec<f64>, f64) {
+    ) -> (Vec<u128>, u128) {
         let mut ret = Vec::from(vols);
-        ret[index] += add;
+        let delta = (add.abs() * ONE as f64) as u128;
+        if add >= 0.0 {
+            ret[index] += delta;
+        } else {
+            ret[index] -= delta;
+        }
 
         let prices = compute_price(LIQ, &ret);
         let new_cost = cost(LIQ, &ret);
-        let amount = new_cost - old_cost;
-
-        assert_eq!(prices[0], ans[0]);
-        assert_eq!(prices[1], ans[1]);
-        assert_eq!(new_cost, ans[2]);
-        assert_eq!(amount, ans[3]);
-
-        let test_vol = volume(LIQ, vols, index, amount);
-        assert!((test_vol - add).abs() < 0.000_000_000_01);
+        let amount = to_f64(new_cost) - to_f64(old_cost);
+
+        assert_close(to_f64(prices[0]), ans[0]);
+        assert_close(to_f64(prices[1]), ans[1]);
+        assert_close(to_f64(new_cost), ans[2]);
+        assert_close(amount, ans[3]);
+
+        // quotes are rounded against the trader
+        let signed = if add >= 0.0 {
+            delta as i128
+        } else {
+            -(delta as i128)
+        };
+        let exact = new_cost as i128 - old_cost as i128;
+        let est = estimate(LIQ, vols, index, signed);
+        assert!(est >= exact);
+        assert!(est - exact <= 2 * cost_error(LIQ, vols.len()) as i128);
+
+        if add > 0.0 {
+            let test_vol = volume(LIQ, vols, index, new_cost - old_cost);
+            assert!((to_f64(test_vol) - add).abs() < 0.000_000_000_01);
+        }
         (ret, new_cost)
     }
 
     #[test]
     fn price_increases() {
-        let volumes: [f64; 2] = [0.0, 0.0];
-        let est = estimate(50.0, &volumes, 1, 10.0);
-        assert!(est > 5.2);
+        let volumes: [u128; 2] = [0, 0];
+        let est = estimate(50 * ONE, &volumes, 1, 10 * ONE as i128);
+        assert!(est > 52 * ONE as i128 / 10);
+    }
+
+    #[test]
+    fn volume_with_budget_far_above_liquidity() {
+        let volumes: [u128; 3] = [7 * ONE, 0, 3 * ONE];
+        for &amount in &[50 * LIQ, 1_000 * LIQ, 10u128.pow(12) * LIQ] {
+            let vol = volume(LIQ, &volumes, 1, amount);
+            let paid = estimate(LIQ, &volumes, 1, vol as i128);
+            assert!((paid - amount as i128).abs() <= 4 * cost_error(LIQ, 3) as i128);
+        }
+    }
+
+    #[test]
+    fn round_trip_never_profits() {
+        let volumes: [u128; 3] = [7 * ONE, 0, 3 * ONE];
+        for &amount in &[1, 1_000, ONE / 3, 25 * ONE] {
+            let paid = estimate(LIQ, &volumes, 1, amount as i128);
+            let mut after = volumes.to_vec();
+            after[1] += amount;
+            let received = -estimate(LIQ, &after, 1, -(amount as i128));
+            assert!(received <= paid);
+        }
     }
 
     #[test]
     fn it_works() {
-        assert_eq!(FUND, fund(LIQ, 2));
-        assert_eq!(LIQ, liquidity(FUND, 2));
+        assert_close(to_f64(fund(LIQ, 2)), FUND);
+        assert!(fund(LIQ, 2) as f64 >= FUND * ONE as f64);
+        assert!(liquidity(fund(LIQ, 2), 2) <= LIQ);
+        assert_close(to_f64(liquidity(fund(LIQ, 2), 2)), 100.0);
 
-        let volumes: [f64; 2] = [0.0, 0.0];
+        let volumes: [u128; 2] = [0, 0];
 
         // initialize
         let prices = compute_price(LIQ, &volumes);
         let cost = cost(LIQ, &volumes);
 
-        assert_eq!(prices[0], 0.5);
-        assert_eq!(prices[1], 0.5);
-        assert_eq!(cost, FUND);
+        assert_eq!(prices[0], ONE / 2);
+        assert_eq!(prices[1], ONE / 2);
+        assert_close(to_f64(cost), FUND);
 
         // 1st
         /*
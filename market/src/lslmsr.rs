@@ -0,0 +1,143 @@
+//! Liquidity-sensitive LMSR (Othman, Pennock, Reeves & Sandholm).
+//!
+//! The liquidity parameter grows with the outstanding volume,
+//! b(q) = alpha * sum(q), so prices move less as a market gets busier. It
+//! never drops below `floor`, the market's initial liquidity, which keeps the
+//! rule defined for an empty market and bounds its loss like plain LMSR with
+//! b = floor. In that region prices are the LMSR prices; above it they sum to
+//! more than one, the vig that funds the growing depth.
+//!
+//! Units and rounding follow the `lmsr` module; `alpha` is fixed point.
+//! Quotes and the volume inverse come from `mechanism::MarketMaker`.
+
+use crate::lmsr;
+use crate::math::{self, ONE};
+
+/// b(q) = max(floor, alpha * sum(q))
+pub fn liquidity(alpha: u128, floor: u128, volumes: &[u128]) -> u128 {
+    let total: u128 = volumes.iter().sum();
+    math::mul_div(alpha, total, ONE).max(floor)
+}
+
+/// Upper bound on the absolute error of [`cost`]
+pub fn cost_error(alpha: u128, floor: u128, volumes: &[u128]) -> u128 {
+    // b itself is rounded down by up to one unit, which moves the cost by at
+    // most ln(n) < n units
+    let b = liquidity(alpha, floor, volumes);
+    lmsr::cost_error(b, volumes.len()) + volumes.len() as u128
+}
+
+/// C(q) = b(q) * ln(sum(e^(q_i / b(q))))
+pub fn cost(alpha: u128, floor: u128, volumes: &[u128]) -> u128 {
+    lmsr::cost(liquidity(alpha, floor, volumes), volumes)
+}
+
+/// Marginal prices, in fixed point. Above the liquidity floor they sum to
+/// more than `ONE`.
+pub fn compute_price(alpha: u128, floor: u128, volumes: &[u128]) -> Vec<u128> {
+    let b = liquidity(alpha, floor, volumes);
+    let total: u128 = volumes.iter().sum();
+    if b == floor {
+        return lmsr::compute_price(b, volumes);
+    }
+
+    // p_i = alpha * ln(S) + w_i / S + (max - sum(q_j * w_j) / S) / Q
+    // with w_j = e^((q_j - max) / b), S = sum(w_j) and Q = sum(q_j)
+    let (weights, max) = lmsr::coefficient(b, volumes);
+    let sum: u128 = weights.iter().sum();
+    let vig = math::mul_div(alpha, math::ln(sum), ONE);
+    let weighted: u128 = volumes
+        .iter()
+        .zip(weights.iter())
+        .map(|(&q, &w)| math::mul_div(q, w, sum))
+        .sum();
+    let spread = math::mul_div(max.saturating_sub(weighted), ONE, total);
+
+    weights
+        .iter()
+        .map(|&w| vig + math::mul_div(w, ONE, sum) + spread)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mechanism::{LsLmsr, MarketMaker};
+
+    const ALPHA: u128 = ONE / 20;
+    const FLOOR: u128 = 100 * ONE;
+
+    #[test]
+    fn matches_lmsr_below_floor() {
+        let volumes = [300 * ONE, 500 * ONE];
+        assert_eq!(liquidity(ALPHA, FLOOR, &volumes), FLOOR);
+        assert_eq!(cost(ALPHA, FLOOR, &volumes), lmsr::cost(FLOOR, &volumes));
+        assert_eq!(
+            compute_price(ALPHA, FLOOR, &volumes),
+            lmsr::compute_price(FLOOR, &volumes)
+        );
+    }
+
+    #[test]
+    fn liquidity_grows_with_volume() {
+        let volumes = [3_000 * ONE, 1_000 * ONE];
+        assert_eq!(liquidity(ALPHA, FLOOR, &volumes), 200 * ONE);
+
+        // the same trade moves the price less in the deeper market
+        let shallow = [300 * ONE, 100 * ONE];
+        let price = |v: &[u128]| compute_price(ALPHA, FLOOR, v)[1];
+        let mut after_deep = volumes.to_vec();
+        after_deep[1] += 50 * ONE;
+        let mut after_shallow = shallow.to_vec();
+        after_shallow[1] += 50 * ONE;
+        assert!(price(&after_deep) - price(&volumes) < price(&after_shallow) - price(&shallow));
+    }
+
+    #[test]
+    fn prices_include_vig_and_match_derivative() {
+        let volumes = [3_000 * ONE, 1_000 * ONE, 2_500 * ONE];
+        let prices = compute_price(ALPHA, FLOOR, &volumes);
+        assert!(prices.iter().sum::<u128>() > ONE);
+
+        let step = ONE / 1_000;
+        for (index, &price) in prices.iter().enumerate() {
+            let mut after = volumes.to_vec();
+            after[index] += step;
+            let numeric = math::mul_div(
+                cost(ALPHA, FLOOR, &after) - cost(ALPHA, FLOOR, &volumes),
+                ONE,
+                step,
+            );
+            let diff = numeric.abs_diff(price);
+            assert!(diff < ONE / 10_000, "outcome {}", index);
+        }
+    }
+
+    #[test]
+    fn volume_inverts_estimate() {
+        let volumes = [3_000 * ONE, 1_000 * ONE];
+        let amount = 37 * ONE;
+        let mm = LsLmsr {
+            alpha: ALPHA,
+            floor: FLOOR,
+        };
+        let bought = mm.volume(&volumes, 1, amount);
+        assert!(mm.estimate(&volumes, 1, bought as i128) <= amount as i128);
+        assert!(mm.estimate(&volumes, 1, bought as i128 + 1) > amount as i128);
+    }
+
+    #[test]
+    fn round_trip_never_profits() {
+        let volumes = [3_000 * ONE, 1_000 * ONE];
+        let amount = 400 * ONE;
+        let mm = LsLmsr {
+            alpha: ALPHA,
+            floor: FLOOR,
+        };
+        let paid = mm.estimate(&volumes, 0, amount as i128);
+        let mut after = volumes.to_vec();
+        after[0] += amount;
+        let received = -mm.estimate(&after, 0, -(amount as i128));
+        assert!(received <= paid);
+    }
+}
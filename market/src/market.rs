@@ -1,14 +1,19 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
-use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, Vector};
-use near_sdk::json_types::U128;
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{I128, U128};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, log, AccountId, Balance, Promise, PromiseOrValue};
+use near_sdk::{env, log, AccountId, Balance, PromiseOrValue};
 
+use crate::circuit_breaker::*;
+use crate::config::Config;
 use crate::constants::*;
 use crate::lmsr;
+use crate::market_roles::RoleTransfer;
+use crate::math;
+use crate::mechanism::{Cpmm, Lmsr, LsLmsr, MarketMaker};
+use crate::resolution::*;
 
 pub type Timestamp = u64;
 
@@ -21,6 +26,10 @@ pub enum Stage {
     Open,
     /// Trading is paused.
     Paused,
+    /// A resolution has been proposed and can be disputed.
+    Proposed,
+    /// The proposed resolution was disputed and awaits the arbiter.
+    Disputed,
     /// The market has been resolved.
     Finalized(Finalization),
 }
@@ -32,6 +41,41 @@ pub enum Finalization {
     Invalid,
 }
 
+/// Market scoring rule used to price trades
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PricingModel {
+    /// LMSR with the fixed `liquidity` parameter
+    Lmsr,
+    /// Liquidity-sensitive LMSR with b = max(liquidity, alpha * sum(q)).
+    /// `alpha` is scaled by 10^18.
+    LsLmsr { alpha: U128 },
+    /// Fixed-product market maker seeded with `liquidity` tokens of every
+    /// outcome
+    Cpmm,
+}
+
+/// Holder of a withdrawable fee balance. Liquidity providers collect their
+/// part together with their liquidity.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FeeRecipient {
+    Protocol,
+    Creator,
+}
+
+/// How holders of an invalid market are refunded
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RefundRule {
+    /// The trading collateral is split evenly across all outstanding shares
+    ProRata,
+    /// Every account gets back what it paid in net of what it received,
+    /// scaled down if the trading collateral does not cover all of it. Any
+    /// surplus goes to the liquidity providers.
+    CostBasis,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Outcome {
@@ -42,14 +86,24 @@ pub struct Outcome {
 
 pub type OutcomeId = u32;
 
+/// Descriptive part of a market. It is stored apart from the trading state
+/// and only loaded by views, so trades cost the same however long it is.
 #[derive(BorshDeserialize, BorshSerialize)]
-pub struct Market {
-    pub id: u64,
+pub struct MarketMetadata {
     pub title: String,
     pub description: String,
+    pub outcomes: Vec<Outcome>,
+}
+
+/// Trading state of a market
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Market {
+    pub id: u64,
 
     pub collateral_token: AccountId,
     pub collateral_decimals: u32,
+    /// Decimals of collateral precision prices are rounded to
+    pub rounding_decimals: u32,
     pub deposited_collateral: Balance,
     pub minimum_deposit: Balance,
 
@@ -58,16 +112,36 @@ pub struct Market {
     /// unix ts in nanoseconds
     pub resolution_time: Timestamp,
 
-    pub outcomes: Vector<Outcome>,
-    pub liquidity: f64,
+    /// Liquidity parameter of the pricing model, in collateral base units:
+    /// b for LMSR, the floor of b for LS-LMSR and the initial pool balance of
+    /// every outcome for CPMM. Until the market opens this is the least the
+    /// deposits must fund; opening raises it to what they do fund.
+    pub liquidity: Balance,
+    pub pricing: PricingModel,
     /// Number of outstanding shares per outcome
-    pub shares: Vec<f64>,
+    pub shares: Vec<Balance>,
     /// Payout weights. For a valid market, weights must sum to 1 of the
     /// collateral token taking in terms of its precision (e.g., if collateral
     /// has 18 decimals, must sum to 10^18). For invalid markets, weights must
     /// all be 0.
     pub payouts: Option<Vec<Balance>>,
+    /// How the market is resolved
+    pub resolution_mode: ResolutionMode,
+    /// Pending optimistic resolution
+    pub proposal: Option<Proposal>,
+    /// Data request to the oracle contract of an externally resolved market
+    pub oracle_request: Option<OracleRequest>,
+    /// Committee votes cast so far
+    pub votes: Vec<Vote>,
+    /// Bonds returned to, or won by, their accounts and not yet withdrawn
+    pub bonds: LookupMap<AccountId, Balance>,
+    /// Set once the market is resolved
+    pub resolution: Option<Resolution>,
 
+    /// Account that created the market and gets the creation bond back
+    pub creator: AccountId,
+    /// NEAR held until the market is finalized
+    pub creation_bond: Balance,
     /// Account responsible for resolving the market
     pub oracle: AccountId,
     /// Account responsible for making admin changes, such as starting the
@@ -75,16 +149,84 @@ pub struct Market {
     pub operator: AccountId,
 
     pub stage: Stage,
+    /// Why the operator or the circuit breaker paused trading
+    pub pause_reason: Option<String>,
+    /// Pauses trading on large price moves
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Recent price ranges checked by the circuit breaker, allocated in full
+    /// when the market opens
+    pub price_window: Option<PriceWindow>,
 
+    /// Receives the creator part of the trade fees
     pub fee_owner: AccountId,
+    /// Proposed transfers of the operator, oracle and fee owner roles
+    pub role_transfers: Vec<RoleTransfer>,
+    /// Fee charged on the base price of every trade
     pub trade_fee_bps: u16,
-    /// Pool fees accrued
-    pub fees_accrued: Balance,
+    /// Parts of each fee that go to the protocol and to `fee_owner`. Liquidity
+    /// providers get the rest.
+    pub protocol_fee_share_bps: u16,
+    pub creator_fee_share_bps: u16,
+    /// Withdrawable fee balances
+    pub protocol_fees_accrued: Balance,
+    pub creator_fees_accrued: Balance,
+    /// Fees owed to liquidity providers, paid out with the LP pool
+    pub lp_fees_accrued: Balance,
     /// Running tally of total trade volume
     pub volume: Balance,
+    /// Collateral paid out to holders of outcome tokens after finalization
+    pub total_redeemed: Balance,
+    /// Complete sets minted and not yet burned. Each is backed by one share
+    /// unit of collateral.
+    pub complete_sets: Balance,
+    /// Collateral paid in by buyers net of what sellers received, excluding
+    /// fees
+    pub trading_collateral: Balance,
 
     /// Outcome token balances of market participants
     pub accounts: LookupMap<AccountId, OutcomeBalance>,
+
+    pub refund_rule: RefundRule,
+    /// Collateral each account paid for shares and complete sets net of what
+    /// it received for them, excluding fees
+    pub cost_basis: LookupMap<AccountId, i128>,
+    /// Sum of all positive cost bases
+    pub total_cost_basis: Balance,
+    /// Collateral left for refunds of an invalid market and the refund units
+    /// (shares or cost basis) it is owed to. Set by the first redemption.
+    pub refund_pool: Option<Balance>,
+    pub refund_units: Balance,
+
+    /// Liquidity provider shares, one per unit of collateral deposited
+    pub lp_shares: LookupMap<AccountId, Balance>,
+    pub total_lp_shares: Balance,
+    /// Collateral left to liquidity providers after finalization: the unused
+    /// subsidy plus all fees. Set by the first withdrawal.
+    pub lp_pool: Option<Balance>,
+}
+
+/// Every layout a market has been stored with in keyed storage. A new layout
+/// gets a new variant, and `migrate_markets` moves markets of the older ones
+/// to it. Markets of the first release were stored untagged in a `Vector`,
+/// see `migration`. Generic so a borrowed market encodes like an owned one.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedMarket<M = Market> {
+    V2(M),
+}
+
+impl VersionedMarket {
+    /// Encodes `market` as the current version without taking it
+    pub fn encode(market: &Market) -> Vec<u8> {
+        VersionedMarket::V2(market).try_to_vec().unwrap()
+    }
+}
+
+impl From<VersionedMarket> for Market {
+    fn from(market: VersionedMarket) -> Self {
+        match market {
+            VersionedMarket::V2(market) => market,
+        }
+    }
 }
 
 /// A type representing outcome token balances of a market participant. The
@@ -97,85 +239,282 @@ pub struct CreateMarketArgs {
     pub title: String,
     pub description: String,
 
+    /// Must be registered. Its decimals come from the registry.
     pub collateral_token: AccountId,
-    pub collateral_decimals: u32,
     pub end_time: Timestamp,
     pub resolution_time: Timestamp,
+    /// Capped by the config
     pub trade_fee_bps: u16,
+    /// Part of each fee paid to `fee_owner`, capped by the config. Defaults
+    /// to none.
+    pub creator_fee_share_bps: Option<u16>,
 
     pub outcomes: Vec<Outcome>,
-    pub liquidity: Option<f64>,
+    /// Least liquidity parameter of the pricing model in collateral base
+    /// units. The market opens with the deepest one its deposits fund.
+    /// Defaults to the config's default liquidity.
+    pub liquidity: Option<U128>,
+    /// Defaults to `PricingModel::Lmsr`
+    pub pricing: Option<PricingModel>,
+    /// Defaults to `RefundRule::ProRata`
+    pub refund_rule: Option<RefundRule>,
 
     pub fee_owner: Option<AccountId>,
     pub operator: Option<AccountId>,
     pub oracle: Option<AccountId>,
+    /// Defaults to `ResolutionMode::Oracle`
+    pub resolution_mode: Option<ResolutionMode>,
 }
 
+/// A redemption whose collateral transfer is still in flight
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingRedemption {
+    pub market_id: u64,
+    pub account_id: AccountId,
+    /// Burned outcome token balances
+    pub balances: Vec<U128>,
+    pub payout: U128,
+    /// Refund units claimed from an invalid market
+    pub refund_units: U128,
+    /// Cost basis removed, in a market refunded by cost basis
+    pub cost_basis: Option<I128>,
+}
+
+/// A sale whose collateral transfer is still in flight
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingSale {
+    pub seller_id: AccountId,
+    pub outcome_id: OutcomeId,
+    pub num_shares: U128,
+    pub amount: U128,
+    pub fee: U128,
+    /// Complete sets the market maker took in to cover the sale
+    pub num_sets: U128,
+}
+
+#[derive(Clone, Copy)]
 pub enum OrderDirection {
     Buy,
     Sell,
 }
 
 impl Market {
-    pub fn new(id: u64, args: CreateMarketArgs) -> Self {
-        let mut outcomes = Vector::new(format!("outcomes{}", id).as_bytes().to_vec());
-        outcomes.extend(args.outcomes);
+    pub fn new(
+        id: u64,
+        args: CreateMarketArgs,
+        collateral_decimals: u32,
+        config: &Config,
+    ) -> (Self, MarketMetadata) {
+        let metadata = MarketMetadata {
+            title: args.title,
+            description: args.description,
+            outcomes: args.outcomes,
+        };
 
-        let creator = env::signer_account_id();
+        let creator = env::predecessor_account_id();
         let fee_owner = args.fee_owner.unwrap_or(creator.clone());
         let operator = args.operator.unwrap_or(creator.clone());
         let oracle = args.oracle.unwrap_or(operator.clone());
-        let shares = vec![0.; outcomes.len().try_into().unwrap()];
+        let shares = vec![0; metadata.outcomes.len()];
+        let resolution_mode = args.resolution_mode.unwrap_or(ResolutionMode::Oracle);
+        resolution_mode.assert_valid(args.resolution_time);
 
-        Self {
+        let market = Self {
             id,
-            title: args.title,
-            description: args.description,
-
-            outcomes,
             payouts: None,
+            resolution_mode,
+            proposal: None,
+            votes: vec![],
+            oracle_request: None,
+            bonds: LookupMap::new(format!("bonds{}", id).as_bytes().to_vec()),
+            resolution: None,
 
             end_time: args.end_time,
             resolution_time: args.resolution_time,
             stage: Stage::Pending,
+            pause_reason: None,
+            circuit_breaker: config.circuit_breaker.clone(),
+            price_window: None,
 
             // TODO(sbb): append something market specific to key
             accounts: LookupMap::new(format!("accmap{}", id).as_bytes().to_vec()),
+            refund_rule: args.refund_rule.unwrap_or(RefundRule::ProRata),
+            cost_basis: LookupMap::new(format!("basis{}", id).as_bytes().to_vec()),
+            total_cost_basis: 0,
+            refund_pool: None,
+            refund_units: 0,
+            lp_shares: LookupMap::new(format!("lpmap{}", id).as_bytes().to_vec()),
+            total_lp_shares: 0,
+            lp_pool: None,
             collateral_token: args.collateral_token,
-            collateral_decimals: args.collateral_decimals,
+            collateral_decimals,
+            rounding_decimals: config.rounding_decimals,
             deposited_collateral: 0,
-            minimum_deposit: MINIMUM_DEPOSIT * (10 as u128).pow(args.collateral_decimals),
+            minimum_deposit: Config::base_units(config.minimum_deposit, collateral_decimals),
 
             liquidity: match args.liquidity {
-                None => DEFAULT_LIQUIDITY,
-                Some(l) => l,
+                None => Config::base_units(config.default_liquidity, collateral_decimals),
+                Some(l) => l.into(),
             },
+            pricing: args.pricing.unwrap_or(PricingModel::Lmsr),
             trade_fee_bps: args.trade_fee_bps,
-            fees_accrued: 0,
+            protocol_fee_share_bps: config.protocol_fee_share_bps,
+            creator_fee_share_bps: args.creator_fee_share_bps.unwrap_or(0),
+            protocol_fees_accrued: 0,
+            creator_fees_accrued: 0,
+            lp_fees_accrued: 0,
             volume: 0,
+            total_redeemed: 0,
+            complete_sets: 0,
+            trading_collateral: 0,
 
             fee_owner,
+            role_transfers: vec![],
+            creator,
+            creation_bond: env::attached_deposit(),
             operator,
             oracle,
             shares,
-        }
+        };
+        (market, metadata)
+    }
+
+    pub fn num_outcomes(&self) -> u64 {
+        self.shares.len() as u64
     }
 
     pub fn open(&mut self) {
         self.validate();
         self.assert_stages(&[Stage::Paused, Stage::Pending]);
+        if self.stage == Stage::Pending {
+            self.liquidity = self.funded_liquidity();
+        }
         self.stage = Stage::Open;
+        self.pause_reason = None;
+        let num_outcomes = self.num_outcomes();
+        self.price_window = self
+            .circuit_breaker
+            .as_ref()
+            .map(|_| PriceWindow::new(num_outcomes));
     }
 
-    pub fn pause(&mut self) {
+    pub fn pause(&mut self, reason: String) {
+        assert!(!reason.trim().is_empty(), "A reason is required");
         self.assert_stage(Stage::Open);
+        log!("Market {} paused: {}", self.id, reason);
         self.stage = Stage::Paused;
+        self.pause_reason = Some(reason);
+    }
+
+    pub fn unpause(&mut self, reason: String) {
+        assert!(!reason.trim().is_empty(), "A reason is required");
+        self.assert_stage(Stage::Paused);
+        log!("Market {} unpaused: {}", self.id, reason);
+        self.open();
     }
 
-    pub fn deposit_collateral(&mut self, amount: u128) {
-        // TODO(sbb): Support paused as well?
+    /// Adds subsidy to a pending market. The depositor receives one LP share
+    /// per unit of collateral, and the market opens deeper by what the
+    /// deposit funds.
+    pub fn deposit_collateral(&mut self, account_id: &AccountId, amount: u128) {
+        // liquidity is only accepted before trading starts, so every unit
+        // deposited is worth the same
         self.assert_stage(Stage::Pending);
         self.deposited_collateral += amount;
+
+        let lp_shares = self.lp_shares.get(account_id).unwrap_or(0);
+        self.lp_shares
+            .insert(account_id, &lp_shares.checked_add(amount).unwrap());
+        self.total_lp_shares = self.total_lp_shares.checked_add(amount).unwrap();
+    }
+
+    /// Burns all LP shares of `account_id` and returns them together with
+    /// their pro-rata part of the LP pool, which the caller must pay out
+    pub fn withdraw_liquidity(&mut self, account_id: &AccountId) -> (Balance, Balance) {
+        self.assert_finalized();
+        let lp_shares = self
+            .lp_shares
+            .remove(account_id)
+            .expect("No liquidity to withdraw");
+
+        let pool = match self.lp_pool {
+            Some(pool) => pool,
+            None => {
+                let pool = self.lp_residual();
+                self.lp_fees_accrued = 0;
+                pool
+            }
+        };
+        let amount = math::mul_div(pool, lp_shares, self.total_lp_shares);
+        self.lp_pool = Some(pool - amount);
+        self.total_lp_shares -= lp_shares;
+
+        (lp_shares, amount)
+    }
+
+    /// Undoes a withdrawal whose collateral transfer failed
+    pub fn restore_liquidity(
+        &mut self,
+        account_id: &AccountId,
+        lp_shares: Balance,
+        amount: Balance,
+    ) {
+        self.lp_shares.insert(account_id, &lp_shares);
+        self.total_lp_shares = self.total_lp_shares.checked_add(lp_shares).unwrap();
+        self.lp_pool = Some(self.lp_pool.unwrap_or(0).checked_add(amount).unwrap());
+    }
+
+    /// Takes the whole fee balance of `recipient`, which the caller must pay
+    /// out
+    pub fn withdraw_fees(&mut self, recipient: FeeRecipient) -> Balance {
+        let accrued = match recipient {
+            FeeRecipient::Protocol => &mut self.protocol_fees_accrued,
+            FeeRecipient::Creator => &mut self.creator_fees_accrued,
+        };
+        assert!(*accrued > 0, "No fees to withdraw");
+        std::mem::take(accrued)
+    }
+
+    /// Undoes a fee withdrawal whose collateral transfer failed
+    pub fn restore_fees(&mut self, recipient: FeeRecipient, amount: Balance) {
+        let accrued = match recipient {
+            FeeRecipient::Protocol => &mut self.protocol_fees_accrued,
+            FeeRecipient::Creator => &mut self.creator_fees_accrued,
+        };
+        *accrued = accrued.checked_add(amount).unwrap();
+    }
+
+    /// Collateral owed to liquidity providers once the market is finalized.
+    /// Traders are owed the payout of every outstanding share; complete sets
+    /// cancel out since payouts sum to one share unit. If the market is
+    /// invalid, traders are refunded from the trading collateral and LPs get
+    /// their deposits back.
+    fn lp_residual(&self) -> Balance {
+        let subsidy = match &self.payouts {
+            Some(payouts) => {
+                let liabilities: Balance = self
+                    .shares
+                    .iter()
+                    .zip(payouts.iter())
+                    .map(|(s, p)| s.checked_mul(*p).unwrap())
+                    .sum();
+                (self.deposited_collateral + self.trading_collateral)
+                    .checked_sub(liabilities)
+                    .unwrap()
+            }
+            None => {
+                let surplus = match self.refund_rule {
+                    RefundRule::ProRata => 0,
+                    RefundRule::CostBasis => self
+                        .refundable_collateral()
+                        .saturating_sub(self.total_cost_basis),
+                };
+                self.deposited_collateral + surplus
+            }
+        };
+        subsidy.checked_add(self.lp_fees_accrued).unwrap()
     }
 
     pub fn calc_buy_price(&self, outcome_id: OutcomeId, num_shares: Balance) -> Balance {
@@ -184,17 +523,126 @@ impl Market {
         base_price.checked_add(fee).unwrap()
     }
 
+    /// Collateral a seller receives, net of fees
     pub fn calc_sell_price(&self, outcome_id: OutcomeId, num_shares: Balance) -> Balance {
         let base_price = self.calc_price_without_fee(outcome_id, num_shares, OrderDirection::Sell);
         let fee = self.calc_fee(base_price);
-        base_price.checked_add(fee).unwrap()
+        base_price.checked_sub(fee).unwrap()
+    }
+
+    /// Value of one outcome share in collateral base units
+    pub fn share_unit(&self) -> Balance {
+        10u128.pow(self.collateral_decimals)
     }
 
-    pub fn calculate_prices(&self) -> Vec<f64> {
-        return lmsr::compute_price(self.liquidity, &self.shares)
+    /// Outstanding shares expressed in collateral base units, as consumed by
+    /// the LMSR functions
+    fn volumes(&self) -> Vec<Balance> {
+        let unit = self.share_unit();
+        self.shares
             .iter()
-            .map(|x| (x * 100.0))
+            .map(|s| s.checked_mul(unit).unwrap())
+            .collect()
+    }
+
+    /// Pricing mechanism selected at creation
+    pub fn market_maker(&self) -> Box<dyn MarketMaker> {
+        match &self.pricing {
+            PricingModel::Lmsr => Box::new(Lmsr {
+                liquidity: self.liquidity,
+            }),
+            PricingModel::LsLmsr { alpha } => Box::new(LsLmsr {
+                alpha: alpha.0,
+                floor: self.liquidity,
+            }),
+            PricingModel::Cpmm => Box::new(Cpmm {
+                funding: self.liquidity,
+            }),
+        }
+    }
+
+    /// Marginal outcome prices as probabilities scaled by `math::ONE`
+    pub fn calculate_prices(&self) -> Vec<u128> {
+        self.market_maker().compute_price(&self.volumes())
+    }
+
+    /// Marginal outcome prices after trading `num_shares` of `outcome_id`
+    pub fn calculate_prices_after(
+        &self,
+        outcome_id: OutcomeId,
+        num_shares: Balance,
+        direction: OrderDirection,
+    ) -> Vec<u128> {
+        let mut volumes = match direction {
+            OrderDirection::Buy => self.volumes(),
+            OrderDirection::Sell => {
+                self.volumes_with_sets(self.sets_to_take(outcome_id, num_shares))
+                    .0
+            }
+        };
+        let amount = num_shares.checked_mul(self.share_unit()).unwrap();
+        let volume = &mut volumes[outcome_id as usize];
+        *volume = match direction {
+            OrderDirection::Buy => volume.checked_add(amount).unwrap(),
+            OrderDirection::Sell => volume.checked_sub(amount).unwrap(),
+        };
+        self.market_maker().compute_price(&volumes)
+    }
+
+    /// Complete sets the market maker has to take in before it can buy back
+    /// `num_shares` of `outcome_id`, which it holds fewer of if the seller
+    /// minted them
+    fn sets_to_take(&self, outcome_id: OutcomeId, num_shares: Balance) -> Balance {
+        let num_sets = num_shares.saturating_sub(self.shares[outcome_id as usize]);
+        assert!(
+            num_sets <= self.complete_sets,
+            "Not enough shares outstanding"
+        );
+        num_sets
+    }
+
+    /// Outstanding volumes once the market maker takes in `num_sets` complete
+    /// sets, and how much that raises its cost beyond the collateral backing
+    /// the sets, rounded up. LMSR and CPMM costs grow by exactly what is added
+    /// to every volume, so their prices stay the same. LS-LMSR deepens its
+    /// liquidity with the volume, and the seller pays for that.
+    fn volumes_with_sets(&self, num_sets: Balance) -> (Vec<Balance>, Balance) {
+        let volumes = self.volumes();
+        if num_sets == 0 {
+            return (volumes, 0);
+        }
+        let backing = num_sets.checked_mul(self.share_unit()).unwrap();
+        let with_sets: Vec<Balance> = volumes
+            .iter()
+            .map(|v| v.checked_add(backing).unwrap())
             .collect();
+        let maker = self.market_maker();
+        let margin = maker.cost_error(&volumes) + maker.cost_error(&with_sets);
+        let excess =
+            (maker.cost(&with_sets) + margin).saturating_sub(maker.cost(&volumes) + backing);
+        (with_sets, excess)
+    }
+
+    /// Moves `num_sets` complete sets and the collateral backing them to the
+    /// outstanding shares of the market maker
+    fn sets_to_market_maker(&mut self, num_sets: Balance) {
+        self.complete_sets = self.complete_sets.checked_sub(num_sets).unwrap();
+        for shares in self.shares.iter_mut() {
+            *shares = shares.checked_add(num_sets).unwrap();
+        }
+        let backing = num_sets.checked_mul(self.share_unit()).unwrap();
+        self.trading_collateral = self.trading_collateral.checked_add(backing).unwrap();
+    }
+
+    /// Moves `num_sets` of every outcome and the collateral backing them out of
+    /// the outstanding shares of the market maker into complete sets
+    fn sets_from_market_maker(&mut self, num_sets: Balance) {
+        for shares in self.shares.iter_mut() {
+            *shares = shares.checked_sub(num_sets).unwrap();
+        }
+        let backing = num_sets.checked_mul(self.share_unit()).unwrap();
+        self.trading_collateral = self.trading_collateral.checked_sub(backing).unwrap();
+        self.complete_sets = self.complete_sets.checked_add(num_sets).unwrap();
     }
 
     pub fn calc_price_without_fee(
@@ -203,125 +651,279 @@ impl Market {
         num_shares: Balance,
         direction: OrderDirection,
     ) -> Balance {
-        let multiplier = match direction {
-            OrderDirection::Buy => 1.0,
-            OrderDirection::Sell => -1.0,
+        let amount: i128 = num_shares
+            .checked_mul(self.share_unit())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let amount = match direction {
+            OrderDirection::Buy => amount,
+            OrderDirection::Sell => -amount,
         };
-        // e.g. 5.2493 (average 0.52 per share for uninitialized market)
-        let estimate = lmsr::estimate(
-            self.liquidity,
-            &self.shares,
-            outcome_id.try_into().unwrap(),
-            multiplier * (num_shares as f64),
-        )
-        .abs();
-
-        // 5.24 * 10 -> 52.4 -> 53 for buy, 52 for sell
-        let multiplier = (10.0 as f64).powi(ROUNDING_DECIMALS as i32);
-        let rounded = match direction {
-            OrderDirection::Buy => (estimate * multiplier).ceil() as u128,
-            OrderDirection::Sell => (estimate * multiplier).floor() as u128,
+        let (volumes, excess) = match direction {
+            OrderDirection::Buy => (self.volumes(), 0),
+            OrderDirection::Sell => {
+                self.volumes_with_sets(self.sets_to_take(outcome_id, num_shares))
+            }
         };
-        let base: u128 = 10;
-        // Multiplied by 10 above so exponent should be 1 less
-        let total = rounded
-            * base
-                .checked_pow(self.collateral_decimals - ROUNDING_DECIMALS)
-                .unwrap();
-        return total;
+        // e.g. 5_249_3xx_xxx (average 0.52 per share for uninitialized market),
+        // already rounded against the trader
+        let estimate = self
+            .market_maker()
+            .estimate(&volumes, outcome_id as usize, amount);
+
+        // 5_249_3xx_xxx -> 5_249_400_000 for buy, 5_249_300_000 for sell
+        let step = 10u128
+            .checked_pow(self.collateral_decimals - self.rounding_decimals)
+            .unwrap();
+        match direction {
+            OrderDirection::Buy => {
+                let cost = estimate.max(0) as u128;
+                cost.div_ceil(step) * step
+            }
+            // a tiny sale can round to a non-positive payout
+            OrderDirection::Sell => {
+                let proceeds = ((-estimate).max(0) as u128).saturating_sub(excess);
+                proceeds / step * step
+            }
+        }
     }
 
+    /// Fee on a trade with the given base price, rounded up so that buyers
+    /// never pay and sellers never receive more than the exact rate implies
     pub fn calc_fee(&self, base_price: Balance) -> Balance {
-        base_price
-            .checked_div(100)
-            .unwrap()
-            .checked_mul(self.trade_fee_bps.into())
-            // .unwrap()
-            // .checked_div(self.collateral_decimals.into())
-            .unwrap()
+        math::mul_div_ceil(base_price, self.trade_fee_bps.into(), BPS_DENOMINATOR)
     }
 
+    /// Splits a collected fee between the protocol, the creator and the
+    /// liquidity providers. The protocol and creator parts are rounded down,
+    /// so the dust goes to the liquidity providers.
     pub fn deposit_fees(&mut self, amount: Balance) {
-        self.fees_accrued = self.fees_accrued.checked_add(amount).unwrap();
-    }
+        let protocol = math::mul_div(amount, self.protocol_fee_share_bps.into(), BPS_DENOMINATOR);
+        let creator = math::mul_div(amount, self.creator_fee_share_bps.into(), BPS_DENOMINATOR);
+        let lp = amount - protocol - creator;
 
-    pub fn withdraw_fees(&mut self) -> Promise {
-        assert!(self.fees_accrued > 0);
-
-        let fees = self.fees_accrued;
-        self.fees_accrued = 0;
-
-        ext_fungible_token::ft_transfer(
-            self.fee_owner.clone(),
-            U128(fees),
-            Some(format!("Withdrawing {} fees to {}", fees, self.fee_owner)),
-            &self.collateral_token,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER,
-        )
+        self.protocol_fees_accrued = self.protocol_fees_accrued.checked_add(protocol).unwrap();
+        self.creator_fees_accrued = self.creator_fees_accrued.checked_add(creator).unwrap();
+        self.lp_fees_accrued = self.lp_fees_accrued.checked_add(lp).unwrap();
     }
 
     pub fn get_or_create_balances(&mut self, account_id: &AccountId) -> OutcomeBalance {
         match self.accounts.get(account_id) {
             Some(a) => a,
-            None => vec![0; self.outcomes.len() as usize],
+            None => vec![0; self.num_outcomes() as usize],
         }
     }
 
-    /// Burn all outcome tokens and redeem for collateral
-    pub fn redeem(&mut self, account_id: &AccountId) -> Promise {
+    /// Burns all outcome tokens of `account_id` and returns the redemption,
+    /// whose payout the caller must transfer
+    pub fn redeem(&mut self, account_id: &AccountId) -> PendingRedemption {
+        self.assert_finalized();
+        let redemption = self
+            .take_redemption(account_id)
+            .expect("No shares to redeem");
+        assert!(redemption.payout.0 > 0, "Nothing to redeem");
+        redemption
+    }
+
+    /// Like [`Market::redeem`], but returns `None` if the account holds no
+    /// outcome tokens. Worthless tokens are burned for a zero payout.
+    pub fn take_redemption(&mut self, account_id: &AccountId) -> Option<PendingRedemption> {
         self.assert_finalized();
-        // TODO(cqsd): error
-        let balances = self.accounts.get(&account_id).expect("no such account");
-        let payout = match &self.payouts {
-            Some(p) => balances
-                .iter()
-                .zip(p.iter())
-                .map(|(b, &p)| b.checked_mul(p).unwrap())
-                .sum(),
-            None => 0,
+        let balances = self.accounts.remove(account_id)?;
+        let cost_basis = self.cost_basis.remove(account_id);
+        let (payout, refund_units) = match &self.payouts {
+            Some(p) => {
+                let payout = balances
+                    .iter()
+                    .zip(p.iter())
+                    .map(|(b, &p)| b.checked_mul(p).unwrap())
+                    .sum();
+                (payout, 0)
+            }
+            None => self.claim_refund(&balances, cost_basis),
         };
-        assert!(payout > 0);
-
-        // TODO(cqsd): handle invalid case (payout == 0)
-        // in that case, outcome tokens are redeemed for equal shares of the pool.
-        // we need to keep track of the total number of shares in circulation for this, which could be a problem...
-
-        ext_fungible_token::ft_transfer(
-            account_id.into(),
-            U128(payout),
-            Some(format!(
-                "Redeeming {} winning tokens for {}",
-                payout,
-                env::current_account_id()
-            )),
-            &self.collateral_token,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER,
-        )
+        self.total_redeemed = self.total_redeemed.checked_add(payout).unwrap();
+
+        Some(PendingRedemption {
+            market_id: self.id,
+            account_id: account_id.clone(),
+            balances: balances.into_iter().map(U128).collect(),
+            payout: U128(payout),
+            refund_units: U128(refund_units),
+            cost_basis: cost_basis.map(I128),
+        })
+    }
+
+    /// Undoes a redemption whose collateral transfer failed
+    pub fn restore_redemption(&mut self, redemption: &PendingRedemption) {
+        let balances: OutcomeBalance = redemption.balances.iter().map(|b| b.0).collect();
+        self.accounts.insert(&redemption.account_id, &balances);
+        self.total_redeemed -= redemption.payout.0;
+
+        if let Some(basis) = redemption.cost_basis {
+            self.cost_basis.insert(&redemption.account_id, &basis.0);
+        }
+        if self.payouts.is_none() {
+            self.refund_pool = Some(self.refund_pool.unwrap() + redemption.payout.0);
+            self.refund_units += redemption.refund_units.0;
+        }
+    }
+
+    /// Collateral held for traders: the trading collateral plus the backing
+    /// of all complete sets
+    fn refundable_collateral(&self) -> Balance {
+        let sets = self.complete_sets.checked_mul(self.share_unit()).unwrap();
+        self.trading_collateral.checked_add(sets).unwrap()
+    }
+
+    /// Takes the refund of an account with `balances` and `cost_basis` in an
+    /// invalid market from the refund pool. Every refund unit is worth the
+    /// same, so the order of claims does not matter. Returns the refund and
+    /// the refund units claimed.
+    fn claim_refund(
+        &mut self,
+        balances: &[Balance],
+        cost_basis: Option<i128>,
+    ) -> (Balance, Balance) {
+        let (pool, units) = match self.refund_pool {
+            Some(pool) => (pool, self.refund_units),
+            None => {
+                let collateral = self.refundable_collateral();
+                match self.refund_rule {
+                    RefundRule::ProRata => {
+                        let num_outcomes = self.num_outcomes() as u128;
+                        let shares: Balance = self.shares.iter().sum();
+                        (collateral, shares + self.complete_sets * num_outcomes)
+                    }
+                    RefundRule::CostBasis => {
+                        (collateral.min(self.total_cost_basis), self.total_cost_basis)
+                    }
+                }
+            }
+        };
+        let account_units = match self.refund_rule {
+            RefundRule::ProRata => balances.iter().sum(),
+            RefundRule::CostBasis => cost_basis.unwrap_or(0).max(0) as Balance,
+        };
+        let refund = if units == 0 {
+            0
+        } else {
+            math::mul_div(pool, account_units, units)
+        };
+
+        self.refund_pool = Some(pool - refund);
+        self.refund_units = units - account_units;
+        (refund, account_units)
+    }
+
+    /// Adds `delta` to the cost basis of `account_id`, which goes negative if
+    /// the account received more than it paid. Only markets refunded by cost
+    /// basis keep track of it.
+    fn adjust_cost_basis(&mut self, account_id: &AccountId, delta: i128) {
+        if self.refund_rule != RefundRule::CostBasis {
+            return;
+        }
+        let old = self.cost_basis.get(account_id).unwrap_or(0);
+        let new = old.checked_add(delta).unwrap();
+        self.cost_basis.insert(account_id, &new);
+        self.total_cost_basis = (self.total_cost_basis + new.max(0) as Balance)
+            .checked_sub(old.max(0) as Balance)
+            .unwrap();
     }
 
     pub fn credit(&mut self, account_id: &AccountId, outcome_id: OutcomeId, num_shares: Balance) {
         self.assert_trading_allowed();
+        self.restore_shares(account_id, outcome_id, num_shares);
+    }
 
-        let mut balances = self.get_or_create_balances(&account_id);
-        balances[outcome_id as usize] += num_shares;
-        self.accounts.insert(&account_id, &balances);
-        self.shares[outcome_id as usize] += num_shares as f64;
+    /// Credits shares regardless of the market stage. Used to undo a debit
+    /// whose collateral transfer failed.
+    pub fn restore_shares(
+        &mut self,
+        account_id: &AccountId,
+        outcome_id: OutcomeId,
+        num_shares: Balance,
+    ) {
+        let mut balances = self.get_or_create_balances(account_id);
+        let balance = &mut balances[outcome_id as usize];
+        *balance = balance
+            .checked_add(num_shares)
+            .expect("Share balance overflow");
+        self.accounts.insert(account_id, &balances);
+        self.shares[outcome_id as usize] = self.shares[outcome_id as usize]
+            .checked_add(num_shares)
+            .unwrap();
+    }
+
+    /// Undoes a sale whose collateral transfer failed
+    pub fn restore_sale(&mut self, sale: &PendingSale) {
+        self.restore_shares(&sale.seller_id, sale.outcome_id, sale.num_shares.into());
+        let base_price = sale.amount.0 + sale.fee.0;
+        self.trading_collateral = self.trading_collateral.checked_add(base_price).unwrap();
+        self.sets_from_market_maker(sale.num_sets.into());
+        self.adjust_cost_basis(&sale.seller_id, base_price.try_into().unwrap());
     }
 
     pub fn debit(&mut self, account_id: &AccountId, outcome_id: OutcomeId, num_shares: Balance) {
         self.assert_trading_allowed();
 
-        let mut balances = self.get_or_create_balances(&account_id);
-        let new_balance = match balances[outcome_id as usize] {
-            s if (s < num_shares) => panic!("shit"),
-            old => old - num_shares,
-        };
-        balances[outcome_id as usize] = new_balance;
-        self.shares[outcome_id as usize] -= num_shares as f64;
+        let mut balances = self.get_or_create_balances(account_id);
+        balances[outcome_id as usize] = balances[outcome_id as usize]
+            .checked_sub(num_shares)
+            .expect("Not enough shares to sell");
+        self.shares[outcome_id as usize] = self.shares[outcome_id as usize]
+            .checked_sub(num_shares)
+            .unwrap();
 
-        self.accounts.insert(&account_id, &balances);
+        self.accounts.insert(account_id, &balances);
+    }
+
+    /// Credits `num_sets` shares of every outcome without moving the LMSR
+    /// state. The caller must have received `num_sets` share units of
+    /// collateral. The market maker takes sets in only when one of their legs
+    /// is sold to it.
+    pub fn mint_complete_sets(&mut self, account_id: &AccountId, num_sets: Balance) {
+        self.assert_trading_allowed();
+        self.restore_complete_sets(account_id, num_sets);
+    }
+
+    /// Credits complete sets regardless of the market stage. Used to undo a
+    /// burn whose collateral transfer failed.
+    pub fn restore_complete_sets(&mut self, account_id: &AccountId, num_sets: Balance) {
+        let mut balances = self.get_or_create_balances(account_id);
+        for balance in balances.iter_mut() {
+            *balance = balance.checked_add(num_sets).unwrap();
+        }
+        self.accounts.insert(account_id, &balances);
+        self.complete_sets = self.complete_sets.checked_add(num_sets).unwrap();
+        let amount = num_sets.checked_mul(self.share_unit()).unwrap();
+        self.adjust_cost_basis(account_id, amount.try_into().unwrap());
+    }
+
+    /// Debits `num_sets` shares of every outcome without moving the LMSR
+    /// state, unless they were bought from the market maker. The caller owes
+    /// `num_sets` share units of collateral.
+    pub fn burn_complete_sets(&mut self, account_id: &AccountId, num_sets: Balance) {
+        self.assert_trading_allowed();
+
+        let mut balances = self.get_or_create_balances(account_id);
+        for balance in balances.iter_mut() {
+            *balance = balance
+                .checked_sub(num_sets)
+                .expect("Not enough shares to burn");
+        }
+        self.accounts.insert(account_id, &balances);
+        if self.complete_sets < num_sets {
+            self.sets_from_market_maker(num_sets - self.complete_sets);
+        }
+        self.complete_sets -= num_sets;
+        let amount: i128 = num_sets
+            .checked_mul(self.share_unit())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        self.adjust_cost_basis(account_id, -amount);
     }
 
     pub fn outcome_balance(
@@ -338,19 +940,33 @@ impl Market {
 
 type ReceiverResponse = PromiseOrValue<U128>;
 
+fn is_expired(valid_until: Option<Timestamp>) -> bool {
+    valid_until.is_some_and(|t| env::block_timestamp() > t)
+}
+
 // internal methods
 impl Market {
+    /// Buys `num_shares` of `outcome_id` and refunds the excess collateral.
+    /// The whole amount is refunded instead if it does not cover the cost, if
+    /// the cost exceeds `max_cost` or if the block is past `valid_until`.
     pub fn internal_buy(
         &mut self,
         sender_id: &AccountId,
         amount: Balance,
         num_shares: Balance,
         outcome_id: OutcomeId,
+        max_cost: Option<Balance>,
+        valid_until: Option<Timestamp>,
     ) -> ReceiverResponse {
         self.assert_trading_allowed();
-        assert!(self.outcomes.len() > outcome_id.into());
+        assert!(self.num_outcomes() > outcome_id.into());
         log!("internal_buy: trading allowed",);
 
+        if is_expired(valid_until) {
+            log!("internal_buy: order expired, refunding");
+            return PromiseOrValue::Value(U128(amount));
+        }
+
         let base_price = self.calc_price_without_fee(outcome_id, num_shares, OrderDirection::Buy);
         let fee = self.calc_fee(base_price);
         let cost = base_price.checked_add(fee).unwrap();
@@ -361,27 +977,139 @@ impl Market {
             cost
         );
         if amount < cost {
-            panic!("Not enough for purchase!");
             // not enough collateral for this buy, cancel the whole thing
+            log!("internal_buy: not enough for purchase, refunding");
+            return PromiseOrValue::Value(U128(amount));
+        }
+        if max_cost.is_some_and(|max_cost| cost > max_cost) {
+            log!("internal_buy: cost exceeds max_cost, refunding");
             return PromiseOrValue::Value(U128(amount));
         }
         // credit the user outcome share balance and return excess collateral
+        let prices_before = self.breaker_reference();
         self.credit(sender_id, outcome_id, num_shares);
         log!("internal_buy: credit complete");
+        self.trading_collateral = self.trading_collateral.checked_add(base_price).unwrap();
+        self.adjust_cost_basis(sender_id, base_price.try_into().unwrap());
         self.deposit_fees(fee);
         log!("internal_buy: fee deposit complete");
-        return PromiseOrValue::Value(U128(amount - cost));
+        self.check_circuit_breaker(prices_before);
+        PromiseOrValue::Value(U128(amount - cost))
     }
 
-    pub fn internal_sell(
+    /// Spends up to `amount` on as many shares of `outcome_id` as it affords
+    /// after fees and refunds the rest. Refunds everything if that is fewer
+    /// than `min_shares`.
+    pub fn internal_buy_with_collateral(
         &mut self,
         sender_id: &AccountId,
         amount: Balance,
+        min_shares: Balance,
+        outcome_id: OutcomeId,
+        valid_until: Option<Timestamp>,
+    ) -> ReceiverResponse {
+        self.assert_trading_allowed();
+        assert!(self.num_outcomes() > outcome_id.into());
+
+        if is_expired(valid_until) {
+            log!("internal_buy_with_collateral: order expired, refunding");
+            return PromiseOrValue::Value(U128(amount));
+        }
+
+        let num_shares = self.max_affordable_shares(outcome_id, amount);
+        log!(
+            "internal_buy_with_collateral: amount: {} affords {} shares",
+            amount,
+            num_shares
+        );
+        if num_shares == 0 || num_shares < min_shares {
+            log!(
+                "internal_buy_with_collateral: below minimum of {} shares, refunding",
+                min_shares
+            );
+            return PromiseOrValue::Value(U128(amount));
+        }
+        self.internal_buy(sender_id, amount, num_shares, outcome_id, None, None)
+    }
+
+    /// Largest number of shares of `outcome_id` whose price including fees
+    /// does not exceed `budget`
+    fn max_affordable_shares(&self, outcome_id: OutcomeId, budget: Balance) -> Balance {
+        self.search_affordable_shares(outcome_id, budget).0
+    }
+
+    /// [`Market::max_affordable_shares`] together with the number of prices
+    /// it computed
+    pub(crate) fn search_affordable_shares(
+        &self,
+        outcome_id: OutcomeId,
+        budget: Balance,
+    ) -> (Balance, u32) {
+        // the volume inverse for the budget net of fees only misses the
+        // rounding, so the answer is a few shares from its estimate
+        let net_budget = math::mul_div(
+            budget,
+            BPS_DENOMINATOR,
+            BPS_DENOMINATOR + self.trade_fee_bps as u128,
+        );
+        let estimate = self
+            .market_maker()
+            .volume(&self.volumes(), outcome_id as usize, net_budget)
+            / self.share_unit();
+        let mut probes = 0;
+        let mut affordable = |num_shares| {
+            probes += 1;
+            num_shares == 0 || self.calc_buy_price(outcome_id, num_shares) <= budget
+        };
+
+        // invariant: `lo` shares are affordable, `hi` shares are not. The
+        // search gallops away from the estimate, then bisects.
+        let (mut lo, mut hi);
+        let mut step = 1;
+        if affordable(estimate) {
+            lo = estimate;
+            hi = lo + step;
+            while affordable(hi) {
+                lo = hi;
+                step *= 2;
+                hi = lo + step;
+            }
+        } else {
+            hi = estimate;
+            lo = hi.saturating_sub(step);
+            while !affordable(lo) {
+                hi = lo;
+                step *= 2;
+                lo = hi.saturating_sub(step);
+            }
+        }
+        while lo + 1 < hi {
+            let mid = lo + (hi - lo) / 2;
+            if affordable(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        (lo, probes)
+    }
+
+    /// Debits `num_shares` of `outcome_id` from the seller and returns the
+    /// sale, whose proceeds and fee the caller must settle. Panics if the
+    /// proceeds fall below `min_amount_out`.
+    pub fn internal_sell(
+        &mut self,
+        sender_id: &AccountId,
+        min_amount_out: Balance,
         num_shares: Balance,
         outcome_id: OutcomeId,
-    ) {
+    ) -> PendingSale {
         self.assert_trading_allowed();
-        assert!(self.outcomes.len() > outcome_id.into());
+        assert!(self.num_outcomes() > outcome_id.into());
+        assert!(
+            self.outcome_balance(sender_id, outcome_id).unwrap_or(0) >= num_shares,
+            "Not enough shares to sell"
+        );
 
         let base_price = self.calc_price_without_fee(outcome_id, num_shares, OrderDirection::Sell);
         let fee = self.calc_fee(base_price);
@@ -394,31 +1122,33 @@ impl Market {
             fee
         );
         let sell_amount = base_price.checked_sub(fee).unwrap();
-        if sell_amount < amount {
+        if sell_amount < min_amount_out {
             panic!("Not executing transaction due to slippage");
         }
-        // credit the user outcome share balance and return excess collateral
+        // provisional until the collateral transfer succeeds
+        let prices_before = self.breaker_reference();
+        let num_sets = self.sets_to_take(outcome_id, num_shares);
+        self.sets_to_market_maker(num_sets);
         self.debit(sender_id, outcome_id, num_shares);
-        self.deposit_fees(fee);
+        self.trading_collateral = self.trading_collateral.checked_sub(base_price).unwrap();
+        self.adjust_cost_basis(sender_id, -i128::try_from(base_price).unwrap());
+        self.check_circuit_breaker(prices_before);
 
-        ext_fungible_token::ft_transfer(
-            sender_id.clone(),
-            U128(sell_amount),
-            Some(format!(
-                "Paying {} for {} shares to {}",
-                sell_amount, num_shares, sender_id
-            )),
-            &self.collateral_token,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER,
-        );
+        PendingSale {
+            seller_id: sender_id.clone(),
+            outcome_id,
+            num_shares: U128(num_shares),
+            amount: U128(sell_amount),
+            fee: U128(fee),
+            num_sets: U128(num_sets),
+        }
     }
 }
 
 // validation
 impl Market {
     fn validate(&self) {
-        assert!(self.outcomes.len() > 0);
+        assert!(self.num_outcomes() > 0);
         assert!(self.end_time > env::block_timestamp());
         assert!(self.resolution_time > env::block_timestamp());
         log!(
@@ -427,25 +1157,43 @@ impl Market {
             self.minimum_deposit
         );
         assert!(self.deposited_collateral >= self.minimum_deposit);
+        // the deposit must cover the worst-case LMSR loss
+        assert!(
+            self.deposited_collateral >= self.market_maker().fund(self.num_outcomes() as usize)
+        );
     }
 
-    fn assert_stages(&self, stages: &[Stage]) {
+    /// Deepest liquidity parameter the deposited collateral subsidizes, and
+    /// at least the one the market was created with
+    fn funded_liquidity(&self) -> Balance {
+        let funded = match self.pricing {
+            // LS-LMSR loses at most what LMSR does at its floor
+            PricingModel::Lmsr | PricingModel::LsLmsr { .. } => {
+                lmsr::liquidity(self.deposited_collateral, self.num_outcomes() as usize)
+            }
+            PricingModel::Cpmm => self.deposited_collateral,
+        };
+        funded.max(self.liquidity)
+    }
+
+    pub(crate) fn assert_stages(&self, stages: &[Stage]) {
         assert!(stages.contains(&self.stage))
     }
 
-    fn assert_stage(&self, stage: Stage) {
+    pub(crate) fn assert_stage(&self, stage: Stage) {
         assert_eq!(self.stage, stage);
     }
 
     fn assert_trading_allowed(&self) {
-        self.assert_stage(Stage::Open);
+        assert_eq!(self.stage, Stage::Open, "Market is not open for trading");
         assert!(env::block_timestamp() < self.end_time);
     }
 
     fn assert_finalized(&self) {
-        assert!(match &self.stage {
-            Stage::Finalized(_) => true,
-            _ => false,
-        });
+        assert!(self.is_finalized());
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        matches!(self.stage, Stage::Finalized(_))
     }
 }
@@ -0,0 +1,113 @@
+//! Two-step transfers of a market's roles. The current holder proposes a
+//! new account, which takes over once it accepts, so a role is never handed
+//! to an account nobody controls.
+
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MarketRole {
+    Operator,
+    Oracle,
+    FeeOwner,
+}
+
+/// A proposed role transfer awaiting acceptance
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RoleTransfer {
+    pub role: MarketRole,
+    pub account_id: AccountId,
+}
+
+impl Market {
+    pub fn role_holder(&self, role: MarketRole) -> &AccountId {
+        match role {
+            MarketRole::Operator => &self.operator,
+            MarketRole::Oracle => &self.oracle,
+            MarketRole::FeeOwner => &self.fee_owner,
+        }
+    }
+
+    fn assert_role_holder(&self, caller: &AccountId, role: MarketRole) {
+        assert_eq!(
+            caller,
+            self.role_holder(role),
+            "Only the {:?} can do this",
+            role
+        );
+    }
+
+    /// Replaces any transfer of `role` already proposed
+    pub fn propose_role_transfer(
+        &mut self,
+        caller: &AccountId,
+        role: MarketRole,
+        account_id: AccountId,
+    ) {
+        self.assert_role_holder(caller, role);
+        self.role_transfers.retain(|t| t.role != role);
+        self.role_transfers.push(RoleTransfer { role, account_id });
+    }
+
+    pub fn cancel_role_transfer(&mut self, caller: &AccountId, role: MarketRole) {
+        self.assert_role_holder(caller, role);
+        self.role_transfers.retain(|t| t.role != role);
+    }
+
+    pub fn accept_role_transfer(&mut self, caller: &AccountId, role: MarketRole) {
+        let index = self
+            .role_transfers
+            .iter()
+            .position(|t| t.role == role && t.account_id == *caller)
+            .expect("No role transfer to accept");
+        self.role_transfers.remove(index);
+        log!(
+            "{:?} of market {} transferred from {} to {}",
+            role,
+            self.id,
+            self.role_holder(role),
+            caller
+        );
+        match role {
+            MarketRole::Operator => self.operator = caller.clone(),
+            MarketRole::Oracle => self.oracle = caller.clone(),
+            MarketRole::FeeOwner => self.fee_owner = caller.clone(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Offers `role` of the market to `account_id`. Only the current holder
+    /// of the role can do this.
+    pub fn propose_role_transfer(
+        &mut self,
+        market_id: MarketId,
+        role: MarketRole,
+        account_id: AccountId,
+    ) {
+        let mut market = self.get_market(market_id);
+        market.propose_role_transfer(&env::predecessor_account_id(), role, account_id);
+        self.save_market(&market);
+    }
+
+    /// Withdraws a pending offer of `role`. Only the current holder of the
+    /// role can do this.
+    pub fn cancel_role_transfer(&mut self, market_id: MarketId, role: MarketRole) {
+        let mut market = self.get_market(market_id);
+        market.cancel_role_transfer(&env::predecessor_account_id(), role);
+        self.save_market(&market);
+    }
+
+    /// Takes over `role` of the market offered to the caller
+    pub fn accept_role_transfer(&mut self, market_id: MarketId, role: MarketRole) {
+        let mut market = self.get_market(market_id);
+        market.accept_role_transfer(&env::predecessor_account_id(), role);
+        self.save_market(&market);
+    }
+}
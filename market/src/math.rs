@@ -0,0 +1,186 @@
+//! Deterministic fixed-point arithmetic for the pricing engine.
+//!
+//! Fixed-point values are `u128` integers scaled by [`ONE`] (18 decimals).
+//! Everything here is plain integer arithmetic, so results are bit-for-bit
+//! identical on-chain and off-chain.
+
+#[allow(clippy::all)]
+mod uint_types {
+    use uint::construct_uint;
+
+    construct_uint! {
+        /// 256-bit unsigned integer for intermediate products
+        pub struct U256(4);
+    }
+}
+
+pub use uint_types::U256;
+
+/// 1.0 in fixed point
+pub const ONE: u128 = 1_000_000_000_000_000_000;
+/// ln(2) scaled by 10^36, rounded down. Range reduction uses the extra
+/// precision so that multiples of ln(2) stay accurate to one unit.
+const LN2_36: u128 = 693_147_180_559_945_309_417_232_121_458_176_568;
+
+/// e^-x is below 10^-18 for every x above this, so [`exp_neg`] returns 0
+pub const EXP_NEG_CUTOFF: u128 = 42 * ONE;
+/// Upper bound on the absolute error of [`exp_neg`], in units of 10^-18.
+/// The Taylor series contributes at most ~26 units and the reciprocal and
+/// final shift one unit each.
+pub const EXP_NEG_ERROR: u128 = 64;
+/// Upper bound on the absolute error of [`ln`], in units of 10^-18. The
+/// atanh series contributes at most ~50 units, the mantissa split and the
+/// ln(2) multiple one unit each.
+pub const LN_ERROR: u128 = 64;
+
+/// `a * b / c`, rounded down. Panics if the result does not fit a `u128`.
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    let res = U256::from(a) * U256::from(b) / U256::from(c);
+    assert!(res <= U256::from(u128::MAX), "math: mul_div overflow");
+    res.as_u128()
+}
+
+/// `a * b / c`, rounded up. Panics if the result does not fit a `u128`.
+pub fn mul_div_ceil(a: u128, b: u128, c: u128) -> u128 {
+    let num = U256::from(a) * U256::from(b);
+    let c = U256::from(c);
+    let mut res = num / c;
+    if !(num % c).is_zero() {
+        res += U256::one();
+    }
+    assert!(res <= U256::from(u128::MAX), "math: mul_div overflow");
+    res.as_u128()
+}
+
+/// Splits `x = n * ln(2) + r` with `r` in `[0, ln(2))`
+fn reduce_ln2(x: u128) -> (u32, u128) {
+    let scaled = x * ONE;
+    let n = scaled / LN2_36;
+    let r = (scaled - n * LN2_36) / ONE;
+    (n as u32, r)
+}
+
+/// e^r for `r` in `[0, ln(2))`, rounded down. The result lies in
+/// `[ONE, 2 * ONE)`.
+fn exp_reduced(r: u128) -> u128 {
+    let mut sum = ONE;
+    let mut term = ONE;
+    let mut k = 1;
+    loop {
+        term = term * r / (k * ONE);
+        if term == 0 {
+            break;
+        }
+        sum += term;
+        k += 1;
+    }
+    sum
+}
+
+/// e^-x for fixed-point `x`, accurate to within [`EXP_NEG_ERROR`]
+pub fn exp_neg(x: u128) -> u128 {
+    if x >= EXP_NEG_CUTOFF {
+        return 0;
+    }
+    let (n, r) = reduce_ln2(x);
+    (ONE * ONE / exp_reduced(r)) >> n
+}
+
+/// Natural logarithm of fixed-point `x >= ONE`, accurate to within
+/// [`LN_ERROR`]
+pub fn ln(x: u128) -> u128 {
+    assert!(x >= ONE, "math: ln argument below one");
+    // x = 2^k * m with m in [1, 2)
+    let k = 127 - (x / ONE).leading_zeros();
+    let m = x >> k;
+
+    // ln(m) = 2 * atanh(z) = 2 * (z + z^3/3 + z^5/5 + ...), z = (m-1)/(m+1) < 1/3
+    let z = (m - ONE) * ONE / (m + ONE);
+    let z2 = z * z / ONE;
+    let mut sum = z;
+    let mut term = z;
+    let mut j = 1;
+    loop {
+        term = term * z2 / ONE;
+        if term == 0 {
+            break;
+        }
+        sum += term / (2 * j + 1);
+        j += 1;
+    }
+
+    k as u128 * LN2_36 / ONE + 2 * sum
+}
+
+/// Natural logarithm of the integer `x >= 1`, in fixed point, accurate to
+/// within [`LN_ERROR`] + 1 for any `x`
+pub fn ln_int(x: u128) -> u128 {
+    assert!(x > 0, "math: ln of zero");
+    // x = 2^k * m with m in [1, 2)
+    let k = 127 - x.leading_zeros();
+    let m = mul_div(x, ONE, 1 << k);
+    k as u128 * LN2_36 / ONE + ln(m)
+}
+
+/// Signed natural logarithm of fixed-point `x > 0`
+pub fn ln_signed(x: u128) -> i128 {
+    assert!(x > 0, "math: ln of zero");
+    if x >= ONE {
+        ln(x) as i128
+    } else {
+        -(ln(ONE * ONE / x) as i128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_f64(x: u128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn from_f64(x: f64) -> u128 {
+        (x * ONE as f64) as u128
+    }
+
+    #[test]
+    fn exp_neg_matches_float() {
+        for &x in &[0.0f64, 1e-9, 0.1, 0.5, 0.693, 1.0, 2.5, 10.0, 30.0, 41.9] {
+            let expected = (-x).exp();
+            let actual = to_f64(exp_neg(from_f64(x)));
+            assert!((actual - expected).abs() < 1e-15, "e^-{}", x);
+        }
+        assert_eq!(exp_neg(0), ONE);
+        assert_eq!(exp_neg(EXP_NEG_CUTOFF), 0);
+    }
+
+    #[test]
+    fn ln_matches_float() {
+        for &x in &[1.0f64, 1.000_001, 1.5, 2.0, 3.0, 10.0, 1234.5678, 1e12] {
+            let expected = x.ln();
+            let actual = to_f64(ln(from_f64(x)));
+            assert!((actual - expected).abs() < 1e-14, "ln {}", x);
+        }
+        assert_eq!(ln(ONE), 0);
+        assert_eq!(ln_signed(ONE / 2), -(ln(2 * ONE) as i128));
+    }
+
+    #[test]
+    fn ln_int_matches_float() {
+        for &x in &[1u128, 2, 3, 1_000, 123_456_789, 10u128.pow(30), u128::MAX] {
+            let expected = (x as f64).ln();
+            let actual = to_f64(ln_int(x));
+            assert!((actual - expected).abs() < 1e-13, "ln {}", x);
+        }
+        assert_eq!(ln_int(1), 0);
+    }
+
+    #[test]
+    fn mul_div_rounding() {
+        assert_eq!(mul_div(10, 10, 3), 33);
+        assert_eq!(mul_div_ceil(10, 10, 3), 34);
+        assert_eq!(mul_div_ceil(10, 9, 3), 30);
+        assert_eq!(mul_div(u128::MAX, ONE, ONE), u128::MAX);
+    }
+}
@@ -0,0 +1,147 @@
+//! Pricing mechanisms behind a common cost-function interface.
+//!
+//! Volumes and amounts share one unit (collateral base units in `Market`) and
+//! prices are scaled by `math::ONE`. Quotes are rounded against the trader.
+
+use std::convert::TryFrom;
+
+use crate::{cpmm, lmsr, lslmsr};
+
+pub trait MarketMaker {
+    /// Cost function C(q)
+    fn cost(&self, volumes: &[u128]) -> u128;
+
+    /// Upper bound on the absolute error of [`MarketMaker::cost`] at `volumes`
+    fn cost_error(&self, volumes: &[u128]) -> u128;
+
+    /// Marginal prices
+    fn compute_price(&self, volumes: &[u128]) -> Vec<u128>;
+
+    /// Subsidy that covers the worst-case loss over `n` outcomes
+    fn fund(&self, n: usize) -> u128;
+
+    /// Cost of changing the volume at `index` by `amount` (negative to sell),
+    /// rounded towards positive infinity
+    fn estimate(&self, origin: &[u128], index: usize, amount: i128) -> i128 {
+        let mut after = Vec::from(origin);
+        after[index] = if amount >= 0 {
+            after[index].checked_add(amount as u128).unwrap()
+        } else {
+            after[index].checked_sub(amount.unsigned_abs()).unwrap()
+        };
+        let before_cost = i128::try_from(self.cost(origin)).unwrap();
+        let after_cost = i128::try_from(self.cost(&after)).unwrap();
+        let margin = self.cost_error(origin) + self.cost_error(&after);
+
+        after_cost - before_cost + i128::try_from(margin).unwrap()
+    }
+
+    /// Volume at `index` that `amount` buys: the inverse of
+    /// [`MarketMaker::estimate`] for purchases. Implementations with a closed
+    /// form may only approximate it; callers that must stay within a budget
+    /// should confirm the result with [`MarketMaker::estimate`].
+    fn volume(&self, origin: &[u128], index: usize, amount: u128) -> u128 {
+        let amount = i128::try_from(amount).unwrap();
+        let affordable = |v: u128| self.estimate(origin, index, v as i128) <= amount;
+
+        // invariant: `lo` is affordable, `hi` is not
+        let mut lo = 0;
+        let mut hi = 1;
+        while affordable(hi) {
+            lo = hi;
+            hi *= 2;
+        }
+        while lo + 1 < hi {
+            let mid = lo + (hi - lo) / 2;
+            if affordable(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+/// Logarithmic market scoring rule with a fixed liquidity parameter
+pub struct Lmsr {
+    pub liquidity: u128,
+}
+
+impl MarketMaker for Lmsr {
+    fn cost(&self, volumes: &[u128]) -> u128 {
+        lmsr::cost(self.liquidity, volumes)
+    }
+
+    fn cost_error(&self, volumes: &[u128]) -> u128 {
+        lmsr::cost_error(self.liquidity, volumes.len())
+    }
+
+    fn compute_price(&self, volumes: &[u128]) -> Vec<u128> {
+        lmsr::compute_price(self.liquidity, volumes)
+    }
+
+    fn fund(&self, n: usize) -> u128 {
+        lmsr::fund(self.liquidity, n)
+    }
+
+    fn estimate(&self, origin: &[u128], index: usize, amount: i128) -> i128 {
+        lmsr::estimate(self.liquidity, origin, index, amount)
+    }
+
+    fn volume(&self, origin: &[u128], index: usize, amount: u128) -> u128 {
+        lmsr::volume(self.liquidity, origin, index, amount)
+    }
+}
+
+/// Liquidity-sensitive LMSR whose liquidity never drops below `floor`
+pub struct LsLmsr {
+    pub alpha: u128,
+    pub floor: u128,
+}
+
+impl MarketMaker for LsLmsr {
+    fn cost(&self, volumes: &[u128]) -> u128 {
+        lslmsr::cost(self.alpha, self.floor, volumes)
+    }
+
+    fn cost_error(&self, volumes: &[u128]) -> u128 {
+        lslmsr::cost_error(self.alpha, self.floor, volumes)
+    }
+
+    fn compute_price(&self, volumes: &[u128]) -> Vec<u128> {
+        lslmsr::compute_price(self.alpha, self.floor, volumes)
+    }
+
+    fn fund(&self, n: usize) -> u128 {
+        // the loss is bounded like plain LMSR at the floor
+        lmsr::fund(self.floor, n)
+    }
+}
+
+/// Fixed-product market maker seeded with `funding` tokens of every outcome
+pub struct Cpmm {
+    pub funding: u128,
+}
+
+impl MarketMaker for Cpmm {
+    fn cost(&self, volumes: &[u128]) -> u128 {
+        cpmm::cost(self.funding, volumes)
+    }
+
+    fn cost_error(&self, volumes: &[u128]) -> u128 {
+        cpmm::cost_error(self.funding, volumes.len())
+    }
+
+    fn compute_price(&self, volumes: &[u128]) -> Vec<u128> {
+        cpmm::compute_price(self.funding, volumes)
+    }
+
+    fn fund(&self, _n: usize) -> u128 {
+        cpmm::fund(self.funding)
+    }
+
+    fn volume(&self, origin: &[u128], index: usize, amount: u128) -> u128 {
+        cpmm::volume(self.funding, origin, index, amount)
+    }
+}
@@ -0,0 +1,254 @@
+//! Upgrades state written by the first release, which is the layout of every
+//! deployment. It is kept here until every deployment has migrated; the next
+//! layout change replaces it.
+
+use near_sdk::collections::Vector;
+use near_sdk::json_types::ValidAccountId;
+
+use crate::market::Finalization;
+use crate::math;
+use crate::*;
+
+/// Contract layout of state version 1, the first release. It kept every
+/// market in a `Vector` and had no owner or config.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct ContractV1 {
+    pub markets: Vector<MarketV1>,
+}
+
+/// Market stages of state version 1
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum StageV1 {
+    Pending,
+    Open,
+    Paused,
+    Finalized(Finalization),
+}
+
+impl From<StageV1> for Stage {
+    fn from(stage: StageV1) -> Self {
+        match stage {
+            StageV1::Pending => Stage::Pending,
+            StageV1::Open => Stage::Open,
+            StageV1::Paused => Stage::Paused,
+            StageV1::Finalized(finalization) => Stage::Finalized(finalization),
+        }
+    }
+}
+
+/// Market layout of state version 1. Liquidity and outstanding shares were
+/// floats in whole collateral tokens and whole shares, and the outcomes had
+/// a `Vector` of their own.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MarketV1 {
+    pub id: u64,
+    pub title: String,
+    pub description: String,
+    pub collateral_token: AccountId,
+    pub collateral_decimals: u32,
+    pub deposited_collateral: Balance,
+    pub minimum_deposit: Balance,
+    pub end_time: Timestamp,
+    pub resolution_time: Timestamp,
+    pub outcomes: Vector<Outcome>,
+    pub liquidity: f64,
+    pub shares: Vec<f64>,
+    pub payouts: Option<Vec<Balance>>,
+    pub oracle: AccountId,
+    pub operator: AccountId,
+    pub stage: StageV1,
+    pub fee_owner: AccountId,
+    pub trade_fee_bps: u16,
+    pub fees_accrued: Balance,
+    pub volume: Balance,
+    pub accounts: LookupMap<AccountId, OutcomeBalance>,
+}
+
+/// Decimals of collateral precision the first release rounded prices to
+const ROUNDING_DECIMALS_V1: u32 = 4;
+
+/// `amount` whole tokens in base units of a token with `decimals`, with the
+/// fraction rounded to the nearest base unit
+fn base_units(amount: f64, decimals: u32) -> Balance {
+    assert!(
+        amount.is_finite() && amount >= 0.0,
+        "Invalid amount {}",
+        amount
+    );
+    let unit = 10u128.pow(decimals);
+    let whole = (amount.trunc() as u128).checked_mul(unit).unwrap();
+    let fraction = (amount.fract() * unit as f64).round() as u128;
+    whole.checked_add(fraction).unwrap()
+}
+
+impl MarketV1 {
+    /// Converts the market to fixed point and separates the trading state
+    /// from the metadata. The outcomes are read from their own storage.
+    ///
+    /// The first release recorded neither who deposited the subsidy nor the
+    /// collateral traders paid in. The deposit becomes LP shares of the
+    /// operator, and the trading collateral is what the cost function charges
+    /// for the outstanding shares. All fees went to the fee owner, and so
+    /// they still do.
+    pub fn upgrade(self) -> (Market, MarketMetadata) {
+        let metadata = MarketMetadata {
+            title: self.title,
+            description: self.description,
+            outcomes: self.outcomes.to_vec(),
+        };
+        let unit = 10u128.pow(self.collateral_decimals);
+        // shares were only ever traded whole, so the floats hold integers
+        let shares: Vec<Balance> = self.shares.iter().map(|&s| base_units(s, 0)).collect();
+        // payouts used to sum to the collateral decimals instead of one share
+        // unit
+        let payouts = self.payouts.map(|payouts| {
+            let total: Balance = payouts.iter().sum();
+            payouts
+                .iter()
+                .map(|&p| math::mul_div(p, unit, total))
+                .collect()
+        });
+        let mut lp_shares = LookupMap::new(format!("lpmap{}", self.id).as_bytes().to_vec());
+        if self.deposited_collateral > 0 {
+            lp_shares.insert(&self.operator, &self.deposited_collateral);
+        }
+
+        let mut market = Market {
+            id: self.id,
+            collateral_token: self.collateral_token,
+            collateral_decimals: self.collateral_decimals,
+            rounding_decimals: ROUNDING_DECIMALS_V1,
+            deposited_collateral: self.deposited_collateral,
+            minimum_deposit: self.minimum_deposit,
+            end_time: self.end_time,
+            resolution_time: self.resolution_time,
+            liquidity: base_units(self.liquidity, self.collateral_decimals),
+            pricing: PricingModel::Lmsr,
+            shares,
+            payouts,
+            resolution_mode: ResolutionMode::Oracle,
+            proposal: None,
+            oracle_request: None,
+            votes: vec![],
+            bonds: LookupMap::new(format!("bonds{}", self.id).as_bytes().to_vec()),
+            resolution: None,
+            creator: self.operator.clone(),
+            creation_bond: 0,
+            oracle: self.oracle,
+            operator: self.operator,
+            stage: self.stage.into(),
+            pause_reason: None,
+            circuit_breaker: None,
+            price_window: None,
+            fee_owner: self.fee_owner,
+            role_transfers: vec![],
+            trade_fee_bps: self.trade_fee_bps,
+            protocol_fee_share_bps: 0,
+            creator_fee_share_bps: BPS_DENOMINATOR as u16,
+            protocol_fees_accrued: 0,
+            creator_fees_accrued: self.fees_accrued,
+            lp_fees_accrued: 0,
+            volume: self.volume,
+            total_redeemed: 0,
+            complete_sets: 0,
+            trading_collateral: 0,
+            accounts: self.accounts,
+            refund_rule: RefundRule::ProRata,
+            cost_basis: LookupMap::new(format!("basis{}", self.id).as_bytes().to_vec()),
+            total_cost_basis: 0,
+            refund_pool: None,
+            refund_units: 0,
+            lp_shares,
+            total_lp_shares: self.deposited_collateral,
+            lp_pool: None,
+        };
+        let volumes: Vec<Balance> = market.shares.iter().map(|s| s * unit).collect();
+        let maker = market.market_maker();
+        market.trading_collateral = maker.cost(&volumes) - maker.cost(&vec![0; volumes.len()]);
+        (market, metadata)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Upgrades the state of the first release once the new code is deployed
+    /// and hands the contract to `owner_id`. The markets are moved afterwards
+    /// by `migrate_markets`. The first release had no owner, so only the
+    /// contract account can do this.
+    #[init(ignore_state)]
+    pub fn migrate(owner_id: ValidAccountId, config: Option<Config>) -> Self {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Only the contract account can migrate"
+        );
+        let old: ContractV1 = env::state_read().expect("No state to migrate");
+        let config = config.unwrap_or_default();
+        config.assert_valid();
+
+        let num_markets = old.markets.len();
+        Self {
+            state_version: STATE_VERSION,
+            owner_id: owner_id.into(),
+            roles: UnorderedMap::new(b"roles".to_vec()),
+            config,
+            paused: false,
+            num_markets,
+            markets: LookupMap::new(b"markets".to_vec()),
+            market_metadata: LookupMap::new(b"metadata".to_vec()),
+            unmigrated_markets: if num_markets > 0 {
+                Some(old.markets)
+            } else {
+                None
+            },
+            collateral_balances: LookupMap::new(b"collateral".to_vec()),
+            collateral_tokens: UnorderedMap::new(b"tokens".to_vec()),
+            storage_accounts: LookupMap::new(b"storage".to_vec()),
+            market_storage_growth: 0,
+        }
+    }
+
+    /// Moves up to `limit` markets of the first release into keyed storage
+    /// with their metadata apart, registers their collateral tokens and
+    /// returns how many are left. Markets can be used again once none are
+    /// left. Only the owner can do this.
+    pub fn migrate_markets(&mut self, limit: u64) -> u64 {
+        self.assert_owner();
+        let mut old = self
+            .unmigrated_markets
+            .take()
+            .expect("No markets to migrate");
+        for _ in 0..limit {
+            let market = match old.pop() {
+                Some(market) => market,
+                None => break,
+            };
+            // a handle on the old outcome storage, to free it once copied
+            let mut outcomes: Vector<Outcome> =
+                BorshDeserialize::try_from_slice(&market.outcomes.try_to_vec().unwrap()).unwrap();
+            if self
+                .collateral_tokens
+                .get(&market.collateral_token)
+                .is_none()
+            {
+                self.collateral_tokens
+                    .insert(&market.collateral_token, &market.collateral_decimals);
+            }
+            let (market, metadata) = market.upgrade();
+            outcomes.clear();
+            self.market_metadata.insert(&market.id, &metadata);
+            self.save_market(&market);
+        }
+
+        let remaining = old.len();
+        log!("{} markets left to migrate", remaining);
+        if remaining > 0 {
+            self.unmigrated_markets = Some(old);
+        }
+        remaining
+    }
+
+    pub fn get_state_version(&self) -> u32 {
+        self.state_version
+    }
+}
@@ -0,0 +1,60 @@
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// Whitelists `token_id` as collateral. Its decimals are read from the
+    /// token's `ft_metadata`; registering a token again refreshes them. Only
+    /// admins can do this.
+    pub fn register_collateral_token(&mut self, token_id: AccountId) -> Promise {
+        self.assert_role(Role::Admin);
+        ext_ft_metadata::ft_metadata(&token_id, 0, GAS_FOR_FT_METADATA).then(
+            ext_self::on_ft_metadata(
+                token_id,
+                &env::current_account_id(),
+                0,
+                GAS_FOR_FT_METADATA_CALLBACK,
+            ),
+        )
+    }
+
+    /// Stores the decimals of a token being registered
+    #[private]
+    pub fn on_ft_metadata(&mut self, token_id: AccountId) -> u8 {
+        assert_eq!(env::promise_results_count(), 1);
+        let metadata = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<FungibleTokenMetadata>(&value)
+                    .expect("Invalid token metadata")
+            }
+            _ => env::panic(b"Failed to fetch token metadata"),
+        };
+        assert!(
+            metadata.decimals as u32 >= self.config.rounding_decimals,
+            "Token has too few decimals"
+        );
+        log!(
+            "Registered collateral token {} with {} decimals",
+            token_id,
+            metadata.decimals
+        );
+        self.collateral_tokens
+            .insert(&token_id, &(metadata.decimals as u32));
+        metadata.decimals
+    }
+
+    /// Removes `token_id` from the whitelist. Existing markets are not
+    /// affected. Only admins can do this.
+    pub fn remove_collateral_token(&mut self, token_id: AccountId) {
+        self.assert_role(Role::Admin);
+        self.collateral_tokens
+            .remove(&token_id)
+            .expect("Collateral token not registered");
+    }
+
+    /// Registered collateral tokens and their decimals
+    pub fn get_collateral_tokens(&self) -> Vec<(AccountId, u32)> {
+        self.collateral_tokens.to_vec()
+    }
+}
@@ -0,0 +1,430 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, AccountId, Balance};
+
+use crate::market::*;
+
+/// How a market is resolved once its resolution time has passed
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ResolutionMode {
+    /// The oracle settles the market directly
+    Oracle,
+    /// The oracle proposes payouts with a bond of at least `bond`. Anyone can
+    /// dispute the proposal within `dispute_window` nanoseconds by posting a
+    /// larger bond, in which case `arbiter` decides. The losing side's bond
+    /// goes to the winner.
+    Optimistic {
+        arbiter: AccountId,
+        bond: U128,
+        dispute_window: u64,
+    },
+    /// Members vote on payouts independently. The first payout vector that
+    /// `threshold` members agree on settles the market; without a quorum by
+    /// `deadline` (unix ts in nanoseconds, after the resolution time) the
+    /// market is invalid.
+    Committee {
+        members: Vec<AccountId>,
+        threshold: u32,
+        deadline: Timestamp,
+    },
+    /// `oracle` is an oracle contract. Once the resolution time has passed
+    /// the operator registers a data request with it, and the payouts it
+    /// sends back through `set_outcome` settle the market.
+    External,
+}
+
+impl ResolutionMode {
+    pub fn assert_valid(&self, resolution_time: Timestamp) {
+        if let ResolutionMode::Committee {
+            members,
+            threshold,
+            deadline,
+        } = self
+        {
+            assert!(
+                *threshold > 0 && *threshold as usize <= members.len(),
+                "Threshold must be between one and the committee size"
+            );
+            let mut unique = members.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), members.len(), "Duplicate committee member");
+            assert!(
+                *deadline > resolution_time,
+                "Voting deadline must be after the resolution time"
+            );
+        }
+    }
+}
+
+/// Who resolved a market and when
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Resolution {
+    pub resolver: AccountId,
+    /// unix ts in nanoseconds
+    pub timestamp: Timestamp,
+    pub finalization: Finalization,
+}
+
+/// Payouts proposed by the oracle of an optimistic market
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Proposal {
+    pub proposer: AccountId,
+    pub payouts: Vec<Balance>,
+    pub bond: Balance,
+    /// unix ts in nanoseconds
+    pub timestamp: Timestamp,
+    pub dispute: Option<Dispute>,
+}
+
+/// Data request registered with an external oracle contract
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleRequest {
+    /// Assigned by the oracle contract once it accepted the request
+    pub request_id: Option<u64>,
+    /// unix ts in nanoseconds
+    pub timestamp: Timestamp,
+}
+
+/// Payouts submitted by a committee member
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Vote {
+    pub member: AccountId,
+    pub payouts: Vec<Balance>,
+    /// unix ts in nanoseconds
+    pub timestamp: Timestamp,
+}
+
+#[derive(Clone, Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Dispute {
+    pub disputer: AccountId,
+    pub bond: Balance,
+    /// unix ts in nanoseconds
+    pub timestamp: Timestamp,
+}
+
+impl Market {
+    /// Settles the market with the oracle's payout vector. Payouts must sum to
+    /// one share unit, or all be zero to declare the market invalid.
+    pub fn resolve(&mut self, resolver: &AccountId, payouts: Vec<Balance>) {
+        assert_eq!(
+            self.resolution_mode,
+            ResolutionMode::Oracle,
+            "Market is not resolved by its oracle"
+        );
+        self.assert_resolvable(resolver);
+        self.finalize(resolver, payouts);
+    }
+
+    /// Opens the dispute window on the oracle's payouts. The oracle has
+    /// transferred `bond`.
+    pub fn propose_resolution(
+        &mut self,
+        proposer: &AccountId,
+        payouts: Vec<Balance>,
+        bond: Balance,
+    ) {
+        let min_bond = match &self.resolution_mode {
+            ResolutionMode::Optimistic { bond, .. } => bond.0,
+            _ => env::panic(b"Market is not resolved optimistically"),
+        };
+        self.assert_resolvable(proposer);
+        assert!(bond >= min_bond, "Bond too small");
+        self.finalization(&payouts);
+
+        log!(
+            "{} proposed payouts {:?} for market {} with bond {}",
+            proposer,
+            payouts,
+            self.id,
+            bond
+        );
+        self.proposal = Some(Proposal {
+            proposer: proposer.clone(),
+            payouts,
+            bond,
+            timestamp: env::block_timestamp(),
+            dispute: None,
+        });
+        self.stage = Stage::Proposed;
+    }
+
+    /// Challenges the pending proposal. The disputer has transferred `bond`,
+    /// which must exceed the proposer's.
+    pub fn dispute_resolution(&mut self, disputer: &AccountId, bond: Balance) {
+        self.assert_stage(Stage::Proposed);
+        let deadline = self.dispute_deadline();
+        let proposal = self.proposal.as_mut().unwrap();
+        assert!(env::block_timestamp() < deadline, "Dispute window closed");
+        assert!(bond > proposal.bond, "Bond must exceed the proposal bond");
+
+        log!(
+            "{} disputed the resolution of market {} with bond {}",
+            disputer,
+            self.id,
+            bond
+        );
+        proposal.dispute = Some(Dispute {
+            disputer: disputer.clone(),
+            bond,
+            timestamp: env::block_timestamp(),
+        });
+        self.stage = Stage::Disputed;
+    }
+
+    /// Records a committee member's payouts and settles the market once
+    /// enough members agree on them
+    pub fn vote(&mut self, member: &AccountId, payouts: Vec<Balance>) {
+        let (threshold, deadline) = match &self.resolution_mode {
+            ResolutionMode::Committee {
+                members,
+                threshold,
+                deadline,
+            } => {
+                assert!(members.contains(member), "Not a committee member");
+                (*threshold as usize, *deadline)
+            }
+            _ => env::panic(b"Market is not resolved by a committee"),
+        };
+        self.assert_stages(&[Stage::Open, Stage::Paused]);
+        assert!(
+            env::block_timestamp() >= self.resolution_time,
+            "Resolution time not reached"
+        );
+        assert!(env::block_timestamp() < deadline, "Voting deadline passed");
+        assert!(
+            self.votes.iter().all(|v| v.member != *member),
+            "Member already voted"
+        );
+        self.finalization(&payouts);
+
+        log!("{} voted {:?} on market {}", member, payouts, self.id);
+        self.votes.push(Vote {
+            member: member.clone(),
+            payouts: payouts.clone(),
+            timestamp: env::block_timestamp(),
+        });
+        let agreeing = self.votes.iter().filter(|v| v.payouts == payouts).count();
+        if agreeing >= threshold {
+            self.finalize(member, payouts);
+        }
+    }
+
+    /// Marks a data request to the external oracle as in flight
+    pub fn request_resolution(&mut self) {
+        assert_eq!(
+            self.resolution_mode,
+            ResolutionMode::External,
+            "Market is not resolved by an oracle contract"
+        );
+        self.assert_stages(&[Stage::Open, Stage::Paused]);
+        assert!(
+            env::block_timestamp() >= self.resolution_time,
+            "Resolution time not reached"
+        );
+        assert!(
+            self.oracle_request.is_none(),
+            "Resolution already requested"
+        );
+
+        self.oracle_request = Some(OracleRequest {
+            request_id: None,
+            timestamp: env::block_timestamp(),
+        });
+    }
+
+    /// Records the ID the oracle contract assigned to the data request, or
+    /// clears the request if the oracle rejected it so it can be retried
+    pub fn on_request_created(&mut self, request_id: Option<u64>) {
+        match request_id {
+            Some(request_id) => {
+                log!(
+                    "Market {} registered request {} with {}",
+                    self.id,
+                    request_id,
+                    self.oracle
+                );
+                self.oracle_request.as_mut().unwrap().request_id = Some(request_id);
+            }
+            None => self.oracle_request = None,
+        }
+    }
+
+    /// Settles the market with the payouts the oracle contract reported for
+    /// its data request
+    pub fn set_outcome(&mut self, caller: &AccountId, request_id: u64, payouts: Vec<Balance>) {
+        assert_eq!(
+            self.resolution_mode,
+            ResolutionMode::External,
+            "Market is not resolved by an oracle contract"
+        );
+        assert_eq!(
+            *caller, self.oracle,
+            "Only the oracle can resolve the market"
+        );
+        self.assert_stages(&[Stage::Open, Stage::Paused]);
+        let expected = self.oracle_request.as_ref().and_then(|r| r.request_id);
+        assert_eq!(expected, Some(request_id), "Unknown oracle request");
+
+        self.finalize(caller, payouts);
+    }
+
+    /// Settles a market whose resolution can no longer be contested: an
+    /// undisputed proposal after its dispute window, which also returns the
+    /// proposer's bond, or a committee without quorum after its deadline,
+    /// which makes the market invalid
+    pub fn finalize_pending(&mut self, caller: &AccountId) {
+        match self.resolution_mode.clone() {
+            ResolutionMode::Optimistic { .. } => self.finalize_proposal(),
+            ResolutionMode::Committee { deadline, .. } => {
+                self.assert_stages(&[Stage::Open, Stage::Paused]);
+                assert!(
+                    env::block_timestamp() >= deadline,
+                    "Voting deadline not reached"
+                );
+                let payouts = vec![0; self.num_outcomes() as usize];
+                self.finalize(caller, payouts);
+            }
+            ResolutionMode::Oracle | ResolutionMode::External => {
+                env::panic(b"Market is resolved by its oracle")
+            }
+        }
+    }
+
+    fn finalize_proposal(&mut self) {
+        self.assert_stage(Stage::Proposed);
+        assert!(
+            env::block_timestamp() >= self.dispute_deadline(),
+            "Dispute window still open"
+        );
+        let proposal = self.proposal.take().unwrap();
+
+        self.credit_bond(&proposal.proposer, proposal.bond);
+        self.finalize(&proposal.proposer, proposal.payouts);
+    }
+
+    /// Settles a disputed market with the arbiter's payouts. If they match the
+    /// proposal the proposer wins the disputer's bond, otherwise the disputer
+    /// wins the proposer's.
+    pub fn arbitrate(&mut self, arbiter: &AccountId, payouts: Vec<Balance>) {
+        match &self.resolution_mode {
+            ResolutionMode::Optimistic { arbiter: a, .. } => {
+                assert_eq!(a, arbiter, "Only the arbiter can settle a dispute")
+            }
+            _ => env::panic(b"Market is not resolved optimistically"),
+        }
+        self.assert_stage(Stage::Disputed);
+        let proposal = self.proposal.take().unwrap();
+        let dispute = proposal.dispute.unwrap();
+
+        let winner = if payouts == proposal.payouts {
+            &proposal.proposer
+        } else {
+            &dispute.disputer
+        };
+        log!(
+            "Arbiter {} settled the dispute of market {} for {}",
+            arbiter,
+            self.id,
+            winner
+        );
+        self.credit_bond(winner, proposal.bond + dispute.bond);
+        self.finalize(arbiter, payouts);
+    }
+
+    /// Settles a market that is not finalized yet regardless of its
+    /// resolution mode, returning the bonds of a pending proposal and dispute
+    pub fn resolve_as_last_resort(&mut self, resolver: &AccountId, payouts: Vec<Balance>) {
+        assert!(!self.is_finalized(), "Market is already finalized");
+        if let Some(proposal) = self.proposal.take() {
+            self.credit_bond(&proposal.proposer, proposal.bond);
+            if let Some(dispute) = proposal.dispute {
+                self.credit_bond(&dispute.disputer, dispute.bond);
+            }
+        }
+        self.finalize(resolver, payouts);
+    }
+
+    /// Takes the whole bond balance of `account_id`, which the caller must pay
+    /// out
+    pub fn withdraw_bond(&mut self, account_id: &AccountId) -> Balance {
+        self.bonds.remove(account_id).expect("No bond to withdraw")
+    }
+
+    /// Credits a bond to `account_id`. Also used to undo a withdrawal whose
+    /// collateral transfer failed.
+    pub fn credit_bond(&mut self, account_id: &AccountId, amount: Balance) {
+        let bond = self.bonds.get(account_id).unwrap_or(0);
+        self.bonds
+            .insert(account_id, &bond.checked_add(amount).unwrap());
+    }
+
+    fn dispute_deadline(&self) -> Timestamp {
+        let window = match &self.resolution_mode {
+            ResolutionMode::Optimistic { dispute_window, .. } => *dispute_window,
+            _ => 0,
+        };
+        self.proposal.as_ref().unwrap().timestamp + window
+    }
+
+    fn assert_resolvable(&self, resolver: &AccountId) {
+        assert_eq!(
+            *resolver, self.oracle,
+            "Only the oracle can resolve the market"
+        );
+        self.assert_stages(&[Stage::Open, Stage::Paused]);
+        assert!(
+            env::block_timestamp() >= self.resolution_time,
+            "Resolution time not reached"
+        );
+    }
+
+    /// Outcome of a payout vector. Panics unless it sums to one share unit or
+    /// is all zero.
+    fn finalization(&self, payouts: &[Balance]) -> Finalization {
+        assert_eq!(self.num_outcomes(), payouts.len() as u64);
+        match payouts.iter().sum::<Balance>() {
+            s if s == self.share_unit() => {
+                let outcome_id = payouts
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, value0), (_, value1)| value0.cmp(value1))
+                    .map(|(idx, _)| idx)
+                    .unwrap() as OutcomeId;
+                Finalization::Resolved { outcome_id }
+            }
+            // no payouts: the outcomes were invalid, put the market in refund mode
+            0 => Finalization::Invalid,
+            _ => env::panic(b"Invalid payout vector"),
+        }
+    }
+
+    fn finalize(&mut self, resolver: &AccountId, payouts: Vec<Balance>) {
+        let finalization = self.finalization(&payouts);
+        self.payouts = match finalization {
+            Finalization::Resolved { .. } => Some(payouts),
+            Finalization::Invalid => None,
+        };
+
+        log!(
+            "Market {} resolved by {} at {}: {:?}",
+            self.id,
+            resolver,
+            env::block_timestamp(),
+            finalization
+        );
+        self.stage = Stage::Finalized(finalization.clone());
+        self.resolution = Some(Resolution {
+            resolver: resolver.clone(),
+            timestamp: env::block_timestamp(),
+            finalization,
+        });
+    }
+}
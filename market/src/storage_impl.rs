@@ -3,44 +3,199 @@ use near_contract_standards::storage_management::{
 };
 
 use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::StorageUsage;
 
 use crate::*;
 
+/// NEAR an account has put down for storage and the bytes its positions use
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct StorageAccount {
+    pub deposit: Balance,
+    /// Bytes used by the account's positions, without the registration
+    pub used: StorageUsage,
+}
+
+impl StorageAccount {
+    /// Deposit locked by the registration and the account's positions
+    pub fn locked(&self) -> Balance {
+        (ACCOUNT_STORAGE_BYTES + self.used) as Balance * env::storage_byte_cost()
+    }
+
+    pub fn available(&self) -> Balance {
+        self.deposit.saturating_sub(self.locked())
+    }
+}
+
+// internal methods
+impl Contract {
+    /// Storage usage to book on an account with `internal_charge_storage`
+    /// once the account's changes are written
+    pub(crate) fn storage_snapshot(&mut self) -> StorageUsage {
+        self.market_storage_growth = 0;
+        env::storage_usage()
+    }
+
+    /// Books the storage used since `initial_storage` on `account_id`, apart
+    /// from any growth of market records. Freed storage is released back to
+    /// the account. With `enforce`, panics if the account hasn't deposited
+    /// enough to cover its new usage.
+    pub(crate) fn internal_charge_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage: StorageUsage,
+        enforce: bool,
+    ) {
+        let market_growth = std::mem::take(&mut self.market_storage_growth);
+        let usage = (env::storage_usage() as i64 - market_growth) as StorageUsage;
+        let mut account = match self.storage_accounts.get(account_id) {
+            Some(account) => account,
+            None => {
+                assert!(
+                    !enforce || usage <= initial_storage,
+                    "Account {} is not registered for storage",
+                    account_id
+                );
+                return;
+            }
+        };
+        if usage >= initial_storage {
+            account.used += usage - initial_storage;
+        } else {
+            account.used = account.used.saturating_sub(initial_storage - usage);
+        }
+        if enforce {
+            assert!(
+                account.locked() <= account.deposit,
+                "Insufficient storage deposit, {} more required",
+                account.locked() - account.deposit
+            );
+        }
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    fn storage_balance(account: &StorageAccount) -> StorageBalance {
+        StorageBalance {
+            total: U128(account.deposit),
+            available: U128(account.available()),
+        }
+    }
+}
+
 #[near_bindgen]
 impl StorageManagement for Contract {
+    /// Registers `account_id`, or the caller, and adds the attached deposit to
+    /// its storage balance. With `registration_only` anything above the
+    /// minimum is refunded.
     #[payable]
     fn storage_deposit(
         &mut self,
         account_id: Option<ValidAccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance {
-        StorageBalance {
-            total: U128(0),
-            available: U128(0),
-        }
+        let amount = env::attached_deposit();
+        let account_id = account_id
+            .map(|a| a.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(mut account) => {
+                if registration_only {
+                    log!("Account {} is already registered", account_id);
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(amount);
+                    }
+                } else {
+                    account.deposit += amount;
+                }
+                account
+            }
+            None => {
+                let min = self.storage_balance_bounds().min.0;
+                assert!(
+                    amount >= min,
+                    "The attached deposit is less than the minimum storage balance"
+                );
+                let deposit = if registration_only {
+                    let refund = amount - min;
+                    if refund > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(refund);
+                    }
+                    min
+                } else {
+                    amount
+                };
+                StorageAccount { deposit, used: 0 }
+            }
+        };
+        self.storage_accounts.insert(&account_id, &account);
+
+        Self::storage_balance(&account)
     }
 
+    /// Refunds `amount`, or all, of the caller's available storage balance
     #[payable]
     fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
-        StorageBalance {
-            total: U128(0),
-            available: U128(0),
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.storage_accounts.get(&account_id).unwrap_or_else(|| {
+            env::panic(format!("Account {} is not registered", account_id).as_bytes())
+        });
+
+        let available = account.available();
+        let amount = amount.map(|a| a.0).unwrap_or(available);
+        assert!(
+            amount <= available,
+            "The amount is greater than the available storage balance"
+        );
+        if amount > 0 {
+            account.deposit -= amount;
+            self.storage_accounts.insert(&account_id, &account);
+            Promise::new(account_id).transfer(amount);
         }
+
+        Self::storage_balance(&account)
     }
 
-    #[allow(unused_variables)]
+    /// Unregisters the caller and refunds its whole storage deposit. Only
+    /// accounts without positions can unregister, so `force` is not
+    /// supported.
     #[payable]
     fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(
+            !force.unwrap_or(false),
+            "Force unregistering is not supported"
+        );
+        let account_id = env::predecessor_account_id();
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(account) => account,
+            None => {
+                log!("Account {} is not registered", account_id);
+                return false;
+            }
+        };
+        assert_eq!(
+            account.used, 0,
+            "Can't unregister an account with open positions"
+        );
+        self.storage_accounts.remove(&account_id);
+        if account.deposit > 0 {
+            Promise::new(account_id).transfer(account.deposit);
+        }
+
         true
     }
 
     fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
-        None
+        self.storage_accounts
+            .get(account_id.as_ref())
+            .map(|account| Self::storage_balance(&account))
     }
 
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
         StorageBalanceBounds {
-            min: U128(0),
+            min: U128(ACCOUNT_STORAGE_BYTES as Balance * env::storage_byte_cost()),
             max: None,
         }
     }
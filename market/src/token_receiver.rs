@@ -1,6 +1,6 @@
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{log, serde_json, PromiseOrValue};
+use near_sdk::{serde_json, PromiseOrValue};
 
 use crate::instructions::*;
 use crate::*;
@@ -13,14 +13,28 @@ impl FungibleTokenReceiver for Contract {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
+        // every instruction trades, deposits or resolves
+        self.assert_not_paused();
         let sender: AccountId = sender_id.into();
         let amount: u128 = amount.into();
         let token_id = env::predecessor_account_id();
         let message = serde_json::from_str::<Instruction>(&msg).expect(errors::INVALID_MESSAGE);
         match message {
             Instruction::Buy(ix) => self.buy(&sender, &token_id, amount, ix),
+            Instruction::BuyWithCollateral(ix) => {
+                self.buy_with_collateral(&sender, &token_id, amount, ix)
+            }
             Instruction::InitialDeposit(ix) => self.deposit(&sender, &token_id, amount, ix),
-            _ => panic!("Not implemented"),
+            Instruction::MintCompleteSets(ix) => {
+                self.mint_complete_sets(&sender, &token_id, amount, ix)
+            }
+            Instruction::ProposeResolution(ix) => {
+                self.propose_resolution(&sender, &token_id, amount, ix)
+            }
+            Instruction::DisputeResolution(ix) => {
+                self.dispute_resolution(&sender, &token_id, amount, ix)
+            }
+            Instruction::Deposit(ix) => self.credit_balance(&sender, &token_id, amount, ix),
         }
     }
 }
@@ -1,3 +1,5 @@
+use crate::circuit_breaker::CircuitBreaker;
+use crate::market_roles::RoleTransfer;
 use crate::*;
 use near_sdk::near_bindgen;
 use near_sdk::serde::{Deserialize, Serialize};
@@ -11,6 +13,7 @@ pub struct MarketView {
 
     pub collateral_token: AccountId,
     pub collateral_decimals: u32,
+    pub rounding_decimals: u32,
     pub deposited_collateral: Balance,
     pub minimum_deposit: Balance,
 
@@ -19,11 +22,32 @@ pub struct MarketView {
 
     pub outcomes: Vec<OutcomeView>,
     /// Number of outstanding shares per outcome
-    pub shares: Vec<f64>,
+    pub shares: Vec<Balance>,
     pub stage: Stage,
+    pub pause_reason: Option<String>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub resolution_mode: ResolutionMode,
+    pub proposal: Option<Proposal>,
+    pub oracle_request: Option<OracleRequest>,
+    pub resolution: Option<Resolution>,
+    pub pricing: PricingModel,
+    pub refund_rule: RefundRule,
     pub trade_fee_bps: u16,
+    pub protocol_fee_share_bps: u16,
+    pub creator_fee_share_bps: u16,
+    pub fee_owner: AccountId,
+    pub operator: AccountId,
+    pub oracle: AccountId,
+    pub role_transfers: Vec<RoleTransfer>,
+    pub creator: AccountId,
+    pub creation_bond: Balance,
+    pub protocol_fees_accrued: Balance,
+    pub creator_fees_accrued: Balance,
+    pub lp_fees_accrued: Balance,
     /// Running tally of total trade volume
     pub volume: Balance,
+    pub total_redeemed: Balance,
+    pub total_lp_shares: Balance,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,7 +56,8 @@ pub struct OutcomeView {
     pub id: OutcomeId,
     pub short_name: String,
     pub long_name: String,
-    pub price: f64,
+    /// Marginal price as a probability scaled by 10^18
+    pub price: U128,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -43,35 +68,104 @@ pub struct BalanceView {
     pub shares: u128,
 }
 
+/// Breakdown of what a trade would cost or pay right now
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QuoteView {
+    /// Collateral moved by the market maker before fees
+    pub base_cost: U128,
+    pub fee: U128,
+    /// Collateral the trader pays for a buy, or receives for a sell
+    pub total: U128,
+    /// `total` per share, rounded down
+    pub average_price: U128,
+    /// Absolute change of the outcome's marginal price, scaled by 10^18
+    pub price_impact: U128,
+    /// Marginal prices of all outcomes after the trade, scaled by 10^18
+    pub prices_after: Vec<U128>,
+}
+
 impl Market {
-    pub fn into_view(self) -> MarketView {
+    pub fn quote(
+        &self,
+        outcome_id: OutcomeId,
+        num_shares: Balance,
+        direction: OrderDirection,
+    ) -> QuoteView {
+        assert!(self.num_outcomes() > outcome_id.into());
+        assert!(num_shares > 0);
+
+        let base_cost = self.calc_price_without_fee(outcome_id, num_shares, direction);
+        let fee = self.calc_fee(base_cost);
+        let total = match direction {
+            OrderDirection::Buy => base_cost.checked_add(fee).unwrap(),
+            OrderDirection::Sell => base_cost.checked_sub(fee).unwrap(),
+        };
+        let price_before = self.calculate_prices()[outcome_id as usize];
+        let prices_after = self.calculate_prices_after(outcome_id, num_shares, direction);
+        let price_after = prices_after[outcome_id as usize];
+        let price_impact = price_after.abs_diff(price_before);
+
+        QuoteView {
+            base_cost: U128(base_cost),
+            fee: U128(fee),
+            total: U128(total),
+            average_price: U128(total / num_shares),
+            price_impact: U128(price_impact),
+            prices_after: prices_after.into_iter().map(U128).collect(),
+        }
+    }
+
+    pub fn into_view(self, metadata: MarketMetadata) -> MarketView {
         let prices = self.calculate_prices();
-        return MarketView {
+        MarketView {
             id: self.id,
-            title: self.title,
-            description: self.description,
+            title: metadata.title,
+            description: metadata.description,
             collateral_token: self.collateral_token,
             collateral_decimals: self.collateral_decimals,
+            rounding_decimals: self.rounding_decimals,
             deposited_collateral: self.deposited_collateral,
             minimum_deposit: self.minimum_deposit,
             end_time: self.end_time,
             resolution_time: self.resolution_time,
-            outcomes: self
+            outcomes: metadata
                 .outcomes
-                .iter()
+                .into_iter()
                 .zip(prices)
                 .map(|(o, p)| OutcomeView {
                     id: o.id,
                     short_name: o.short_name,
                     long_name: o.long_name,
-                    price: p,
+                    price: U128(p),
                 })
                 .collect(),
             shares: self.shares,
             stage: self.stage,
+            pause_reason: self.pause_reason,
+            circuit_breaker: self.circuit_breaker,
+            resolution_mode: self.resolution_mode,
+            proposal: self.proposal,
+            oracle_request: self.oracle_request,
+            resolution: self.resolution,
+            pricing: self.pricing,
+            refund_rule: self.refund_rule,
             trade_fee_bps: self.trade_fee_bps,
+            protocol_fee_share_bps: self.protocol_fee_share_bps,
+            creator_fee_share_bps: self.creator_fee_share_bps,
+            fee_owner: self.fee_owner,
+            operator: self.operator,
+            oracle: self.oracle,
+            role_transfers: self.role_transfers,
+            creator: self.creator,
+            creation_bond: self.creation_bond,
+            protocol_fees_accrued: self.protocol_fees_accrued,
+            creator_fees_accrued: self.creator_fees_accrued,
+            lp_fees_accrued: self.lp_fees_accrued,
             volume: self.volume,
-        };
+            total_redeemed: self.total_redeemed,
+            total_lp_shares: self.total_lp_shares,
+        }
     }
 
     pub fn get_user_balances(&self, account_id: AccountId) -> Vec<BalanceView> {
@@ -95,20 +189,63 @@ impl Market {
 #[near_bindgen]
 impl Contract {
     pub fn get_market_info(&self, market_id: u64) -> MarketView {
-        let market = self.markets.get(market_id).unwrap();
-        return market.into_view();
+        let market = self.get_market(market_id);
+        market.into_view(self.get_market_metadata(market_id))
+    }
+
+    pub fn get_buy_quote(
+        &self,
+        market_id: u64,
+        outcome_id: OutcomeId,
+        num_shares: u64,
+    ) -> QuoteView {
+        let market = self.get_market(market_id);
+        market.quote(outcome_id, num_shares as u128, OrderDirection::Buy)
+    }
+
+    pub fn get_sell_quote(
+        &self,
+        market_id: u64,
+        outcome_id: OutcomeId,
+        num_shares: u64,
+    ) -> QuoteView {
+        let market = self.get_market(market_id);
+        market.quote(outcome_id, num_shares as u128, OrderDirection::Sell)
+    }
+
+    /// Up to `limit` markets, 100 by default, starting at `from_index`
+    pub fn get_all_markets(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<MarketView> {
+        let from_index = from_index.unwrap_or(0);
+        let to_index = from_index
+            .saturating_add(limit.unwrap_or(100))
+            .min(self.num_markets);
+        (from_index..to_index)
+            .map(|id| self.get_market_info(id))
+            .collect()
+    }
+
+    pub fn get_lp_shares(&self, market_id: u64, account_id: AccountId) -> U128 {
+        let market = self.get_market(market_id);
+        U128(market.lp_shares.get(&account_id).unwrap_or(0))
+    }
+
+    pub fn get_votes(&self, market_id: u64) -> Vec<Vote> {
+        let market = self.get_market(market_id);
+        market.votes
+    }
+
+    pub fn get_collateral_balance(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        U128(self.collateral_balance(&account_id, &token_id))
     }
 
-    pub fn get_all_markets(&self) -> Vec<MarketView> {
-        return self.markets.iter().map(|m| m.into_view()).collect();
+    pub fn get_bond(&self, market_id: u64, account_id: AccountId) -> U128 {
+        let market = self.get_market(market_id);
+        U128(market.bonds.get(&account_id).unwrap_or(0))
     }
 
     pub fn get_user_balances(&self, account_id: &AccountId) -> Vec<BalanceView> {
-        return self
-            .markets
-            .iter()
-            .map(|m| m.get_user_balances(account_id.clone()))
-            .flatten()
-            .collect();
+        (0..self.num_markets)
+            .flat_map(|id| self.get_market(id).get_user_balances(account_id.clone()))
+            .collect()
     }
 }
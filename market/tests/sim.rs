@@ -0,0 +1,372 @@
+//! End-to-end tests against the compiled contracts. Build the wasm files
+//! first with `make build`.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk_sim::{init_simulator, to_yocto, ExecutionResult, UserAccount, DEFAULT_GAS};
+
+near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
+    MARKET_WASM => "../target/wasm32-unknown-unknown/release/prediction_market.wasm",
+    TOKEN_WASM => "../target/wasm32-unknown-unknown/release/test_token.wasm",
+    ORACLE_WASM => "../target/wasm32-unknown-unknown/release/mock_oracle.wasm",
+}
+
+const MARKET_ID: &str = "market";
+const TOKEN_ID: &str = "token";
+const ORACLE_ID: &str = "oracle";
+
+/// Blocks are a second apart in the simulator
+const SECOND: u64 = 1_000_000_000;
+
+struct Sim {
+    root: UserAccount,
+    market: UserAccount,
+    token: UserAccount,
+    oracle: UserAccount,
+}
+
+fn call(
+    signer: &UserAccount,
+    contract: &UserAccount,
+    method: &str,
+    args: Value,
+    deposit: u128,
+) -> ExecutionResult {
+    signer.call(
+        contract.account_id(),
+        method,
+        args.to_string().as_bytes(),
+        DEFAULT_GAS,
+        deposit,
+    )
+}
+
+fn view(contract: &UserAccount, method: &str, args: Value) -> Value {
+    contract
+        .view(contract.account_id(), method, args.to_string().as_bytes())
+        .unwrap_json_value()
+}
+
+/// Whether the call or any of the receipts it spawned failed
+fn failed(result: &ExecutionResult) -> bool {
+    !result.is_ok() || !result.promise_errors().is_empty()
+}
+
+impl Sim {
+    /// Deploys the market, a collateral token with the whole supply on root
+    /// and the mock oracle. The token is registered as collateral.
+    fn new() -> Self {
+        let root = init_simulator(None);
+        let token = root.deploy(&TOKEN_WASM, TOKEN_ID.to_string(), to_yocto("10"));
+        call(
+            &root,
+            &token,
+            "new_default_meta",
+            json!({ "owner_id": root.account_id(), "total_supply": U128(to_yocto("1000000")) }),
+            0,
+        )
+        .assert_success();
+        let oracle = root.deploy(&ORACLE_WASM, ORACLE_ID.to_string(), to_yocto("10"));
+        call(&root, &oracle, "new", json!({}), 0).assert_success();
+        let market = root.deploy(&MARKET_WASM, MARKET_ID.to_string(), to_yocto("100"));
+        call(
+            &root,
+            &market,
+            "new",
+            json!({ "owner_id": root.account_id() }),
+            0,
+        )
+        .assert_success();
+
+        let sim = Self {
+            root,
+            market,
+            token,
+            oracle,
+        };
+        sim.register_with_token(&sim.market);
+        let result = call(
+            &sim.root,
+            &sim.market,
+            "register_collateral_token",
+            json!({ "token_id": TOKEN_ID }),
+            0,
+        );
+        assert!(!failed(&result), "{:?}", result.promise_errors());
+        sim
+    }
+
+    fn register_with_token(&self, account: &UserAccount) {
+        call(
+            account,
+            &self.token,
+            "storage_deposit",
+            json!({}),
+            to_yocto("1"),
+        )
+        .assert_success();
+    }
+
+    /// Creates a user holding `tokens` whole collateral tokens and a storage
+    /// deposit with the market
+    fn create_trader(&self, account_id: &str, tokens: &str) -> UserAccount {
+        let user = self
+            .root
+            .create_user(account_id.to_string(), to_yocto("100"));
+        self.register_with_token(&user);
+        call(
+            &self.root,
+            &self.token,
+            "ft_transfer",
+            json!({ "receiver_id": account_id, "amount": U128(to_yocto(tokens)) }),
+            1,
+        )
+        .assert_success();
+        call(
+            &user,
+            &self.market,
+            "storage_deposit",
+            json!({}),
+            to_yocto("1"),
+        )
+        .assert_success();
+        user
+    }
+
+    /// Sends collateral to the market with an instruction
+    fn transfer_call(&self, sender: &UserAccount, amount: u128, msg: Value) -> ExecutionResult {
+        call(
+            sender,
+            &self.token,
+            "ft_transfer_call",
+            json!({ "receiver_id": MARKET_ID, "amount": U128(amount), "msg": msg.to_string() }),
+            1,
+        )
+    }
+
+    fn balance_of(&self, account_id: &str) -> u128 {
+        let balance: U128 = near_sdk::serde_json::from_value(view(
+            &self.token,
+            "ft_balance_of",
+            json!({ "account_id": account_id }),
+        ))
+        .unwrap();
+        balance.0
+    }
+
+    fn market_info(&self, market_id: u64) -> Value {
+        view(
+            &self.market,
+            "get_market_info",
+            json!({ "market_id": market_id }),
+        )
+    }
+
+    fn now(&self) -> u64 {
+        self.root.borrow_runtime().current_block().block_timestamp
+    }
+
+    fn wait(&self, nanos: u64) {
+        self.root
+            .borrow_runtime_mut()
+            .produce_blocks(nanos / SECOND + 1)
+            .unwrap();
+    }
+
+    /// Creates a two outcome market resolved by the mock oracle, funds it
+    /// from root and opens it. It resolves `delay` nanoseconds from now.
+    fn open_external_market(&self, delay: u64) -> u64 {
+        let resolution_time = self.now() + delay;
+        let result = call(
+            &self.root,
+            &self.market,
+            "create_market",
+            json!({ "args": {
+                "title": "sim",
+                "description": "resolved by the mock oracle",
+                "collateral_token": TOKEN_ID,
+                "end_time": resolution_time,
+                "resolution_time": resolution_time,
+                "trade_fee_bps": 0,
+                "outcomes": [
+                    { "id": 0, "short_name": "no", "long_name": "no" },
+                    { "id": 1, "short_name": "yes", "long_name": "yes" },
+                ],
+                "oracle": ORACLE_ID,
+                "resolution_mode": "External",
+            }}),
+            to_yocto("1"),
+        );
+        result.assert_success();
+        let market_id: u64 = result.unwrap_json();
+
+        call(
+            &self.root,
+            &self.market,
+            "storage_deposit",
+            json!({}),
+            to_yocto("1"),
+        )
+        .assert_success();
+        let result = self.transfer_call(
+            &self.root,
+            to_yocto("100"),
+            json!({ "type": "InitialDeposit", "market_id": market_id }),
+        );
+        assert!(!failed(&result), "{:?}", result.promise_errors());
+        call(
+            &self.root,
+            &self.market,
+            "open_market",
+            json!({ "market_id": market_id }),
+            0,
+        )
+        .assert_success();
+        market_id
+    }
+}
+
+#[test]
+fn external_oracle_resolves_market() {
+    let sim = Sim::new();
+    let alice = sim.create_trader("alice", "100");
+    let market_id = sim.open_external_market(100 * SECOND);
+
+    let result = sim.transfer_call(
+        &alice,
+        to_yocto("10"),
+        json!({ "type": "Buy", "market_id": market_id, "outcome_id": 1, "num_shares": 5 }),
+    );
+    assert!(!failed(&result), "{:?}", result.promise_errors());
+    let spent = to_yocto("100") - sim.balance_of("alice");
+    assert!(spent > 0);
+
+    // too early to ask the oracle
+    let result = call(
+        &sim.root,
+        &sim.market,
+        "request_resolution",
+        json!({ "market_id": market_id }),
+        0,
+    );
+    assert!(!result.is_ok());
+
+    sim.wait(100 * SECOND);
+    let result = call(
+        &sim.root,
+        &sim.market,
+        "request_resolution",
+        json!({ "market_id": market_id }),
+        0,
+    );
+    assert!(!failed(&result), "{:?}", result.promise_errors());
+    let info = sim.market_info(market_id);
+    assert_eq!(info["oracle_request"]["request_id"], json!(0));
+    let request = view(&sim.oracle, "get_request", json!({ "request_id": "0" }));
+    assert_eq!(request["requester"], json!(MARKET_ID));
+
+    // anyone can report to the mock oracle, which forwards to set_outcome
+    let result = call(
+        &alice,
+        &sim.oracle,
+        "resolve",
+        json!({ "request_id": "0", "payouts": [U128(0), U128(to_yocto("1"))] }),
+        0,
+    );
+    assert!(!failed(&result), "{:?}", result.promise_errors());
+    let info = sim.market_info(market_id);
+    assert!(
+        info["stage"].get("Finalized").is_some(),
+        "{}",
+        info["stage"]
+    );
+    assert_eq!(info["resolution"]["resolver"], json!(ORACLE_ID));
+
+    // five winning shares redeem for five whole tokens
+    let before = sim.balance_of("alice");
+    let result = call(
+        &alice,
+        &sim.market,
+        "redeem",
+        json!({ "market_id": market_id }),
+        1,
+    );
+    assert!(!failed(&result), "{:?}", result.promise_errors());
+    assert_eq!(sim.balance_of("alice") - before, to_yocto("5"));
+}
+
+#[test]
+fn set_outcome_only_from_oracle() {
+    let sim = Sim::new();
+    let market_id = sim.open_external_market(100 * SECOND);
+    sim.wait(100 * SECOND);
+    let result = call(
+        &sim.root,
+        &sim.market,
+        "request_resolution",
+        json!({ "market_id": market_id }),
+        0,
+    );
+    assert!(!failed(&result), "{:?}", result.promise_errors());
+
+    let result = call(
+        &sim.root,
+        &sim.market,
+        "set_outcome",
+        json!({ "market_id": market_id.to_string(), "request_id": "0", "payouts": [U128(1), U128(0)] }),
+        0,
+    );
+    assert!(!result.is_ok());
+    assert!(sim.market_info(market_id)["resolution"].is_null());
+}
+
+/// Whether the call failed because the contract doesn't export `method`
+fn method_not_found(result: &ExecutionResult) -> bool {
+    format!("{:?}", result.status()).contains("MethodNotFound")
+}
+
+#[test]
+fn deposit_is_not_a_contract_method() {
+    let sim = Sim::new();
+    let market_id = sim.open_external_market(100 * SECOND);
+    let before = sim.market_info(market_id);
+
+    // LP shares are only minted for collateral the token contract transferred
+    let result = call(
+        &sim.root,
+        &sim.market,
+        "deposit",
+        json!({
+            "sender_id": "root",
+            "token_id": TOKEN_ID,
+            "amount": 1_000_000_000_000_000_000u64,
+            "ix": { "market_id": market_id },
+        }),
+        0,
+    );
+    assert!(method_not_found(&result), "{:?}", result.status());
+    assert_eq!(sim.market_info(market_id), before);
+}
+
+#[test]
+fn buy_is_not_a_contract_method() {
+    let sim = Sim::new();
+    let market_id = sim.open_external_market(100 * SECOND);
+    let before = sim.market_info(market_id);
+
+    // shares are only credited for collateral the token contract transferred
+    let result = call(
+        &sim.root,
+        &sim.market,
+        "buy",
+        json!({
+            "sender_id": "root",
+            "token_id": TOKEN_ID,
+            "amount": 1_000_000_000_000_000_000u64,
+            "ix": { "market_id": market_id, "outcome_id": 0, "num_shares": 1 },
+        }),
+        0,
+    );
+    assert!(method_not_found(&result), "{:?}", result.status());
+    assert_eq!(sim.market_info(market_id), before);
+}
@@ -0,0 +1,118 @@
+//! Stand-in for an external oracle contract. Requesters register a data
+//! request and receive the answer through `set_outcome` once anyone reports
+//! it, which lets the market's oracle flow run without a live oracle network.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, log, near_bindgen, AccountId, Gas, PanicOnDefault, Promise};
+
+near_sdk::setup_alloc!();
+
+const GAS_FOR_SET_OUTCOME: Gas = 20_000_000_000_000;
+
+#[ext_contract(ext_requester)]
+pub trait Requester {
+    fn set_outcome(&mut self, market_id: U64, request_id: U64, payouts: Vec<U128>);
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DataRequest {
+    pub requester: AccountId,
+    pub market_id: U64,
+    pub num_outcomes: u32,
+    pub payouts: Option<Vec<U128>>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    requests: Vector<DataRequest>,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            requests: Vector::new(b"r".to_vec()),
+        }
+    }
+
+    /// Registers a request for the payouts of `market_id` on behalf of the
+    /// caller and returns its ID
+    pub fn create_request(&mut self, market_id: U64, num_outcomes: u32) -> U64 {
+        let request_id = self.requests.len();
+        self.requests.push(&DataRequest {
+            requester: env::predecessor_account_id(),
+            market_id,
+            num_outcomes,
+            payouts: None,
+        });
+        log!("Created request {} for market {}", request_id, market_id.0);
+        U64(request_id)
+    }
+
+    /// Answers a request and forwards the payouts to its requester
+    pub fn resolve(&mut self, request_id: U64, payouts: Vec<U128>) -> Promise {
+        let mut request = self.requests.get(request_id.0).expect("No such request");
+        assert!(request.payouts.is_none(), "Request already resolved");
+        assert_eq!(request.num_outcomes as usize, payouts.len());
+
+        request.payouts = Some(payouts.clone());
+        self.requests.replace(request_id.0, &request);
+
+        ext_requester::set_outcome(
+            request.market_id,
+            request_id,
+            payouts,
+            &request.requester,
+            0,
+            GAS_FOR_SET_OUTCOME,
+        )
+    }
+
+    pub fn get_request(&self, request_id: U64) -> Option<DataRequest> {
+        self.requests.get(request_id.0)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    use super::*;
+
+    #[test]
+    fn create_and_resolve_request() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new();
+
+        let request_id = contract.create_request(U64(3), 2);
+        let request = contract.get_request(request_id).unwrap();
+        assert_eq!(request.requester, accounts(1).to_string());
+        assert_eq!(request.market_id, U64(3));
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.resolve(request_id, vec![U128(0), U128(1)]);
+        let request = contract.get_request(request_id).unwrap();
+        assert_eq!(request.payouts, Some(vec![U128(0), U128(1)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Request already resolved")]
+    fn resolve_only_once() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut contract = Contract::new();
+        let request_id = contract.create_request(U64(0), 2);
+        contract.resolve(request_id, vec![U128(0), U128(1)]);
+        contract.resolve(request_id, vec![U128(1), U128(0)]);
+    }
+}